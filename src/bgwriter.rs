@@ -0,0 +1,127 @@
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use crate::buffer::BufferPoolManager;
+
+// 書き込む間隔の既定値
+pub const DEFAULT_WRITER_INTERVAL: Duration = Duration::from_millis(200);
+// 一回に書き込むページ数の上限の既定値
+pub const DEFAULT_WRITER_MAX_PAGES: usize = 100;
+
+#[derive(Debug, Clone, Copy)]
+pub struct BackgroundWriterOptions {
+    // 書き込む間隔
+    pub interval: Duration,
+    // 一回に書き込むページ数の上限 (1 秒あたり max_pages / interval ページまでに抑えられる)
+    pub max_pages: usize,
+}
+
+impl Default for BackgroundWriterOptions {
+    fn default() -> Self {
+        Self {
+            interval: DEFAULT_WRITER_INTERVAL,
+            max_pages: DEFAULT_WRITER_MAX_PAGES,
+        }
+    }
+}
+
+// 変更済みでピン留めされていないバッファを定期的に書き込んでおくスレッド
+// fetch_page が追い出すバッファを書き戻すのを待たずに済むようにする
+// 破棄すると (または stop() で) スレッドを止める
+pub struct BackgroundWriter {
+    stop: Option<Sender<()>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl BackgroundWriter {
+    // スレッドはバッファプールを弱い参照で持つので、バッファプールが破棄されれば終わる
+    pub fn start(bufmgr: &Arc<BufferPoolManager>, options: BackgroundWriterOptions) -> Self {
+        let bufmgr = Arc::downgrade(bufmgr);
+        let (stop, stopped) = mpsc::channel::<()>();
+        // 止めるよう指示されるか BackgroundWriter が破棄されるまで、interval ごとに書き込む
+        let handle = thread::spawn(move || {
+            while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(options.interval) {
+                let Some(bufmgr) = bufmgr.upgrade() else {
+                    break;
+                };
+                // 失敗したページは変更済みのまま残るので、次の回か追い出すときに改めて書き込まれる
+                if let Err(e) = bufmgr.write_dirty_buffers(options.max_pages) {
+                    eprintln!("background writer failed to write buffers: {}", e);
+                }
+            }
+        });
+        Self {
+            stop: Some(stop),
+            handle: Some(handle),
+        }
+    }
+
+    // スレッドが止まるまで待つ
+    pub fn stop(self) {
+        drop(self);
+    }
+}
+
+impl Drop for BackgroundWriter {
+    fn drop(&mut self) {
+        // 送信側を閉じるとスレッドの recv_timeout が終わる
+        self.stop.take();
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::buffer::BufferPool;
+    use crate::store::{MemoryPageStore, PageStore};
+    use std::time::Instant;
+
+    const PAGE_SIZE: usize = 4096;
+
+    #[test]
+    fn writes_at_most_max_pages_per_round_until_stopped() {
+        let store = MemoryPageStore::new(PAGE_SIZE).unwrap();
+        let page_ids: Vec<_> = (0..6).map(|_| store.allocate_page().unwrap()).collect();
+        let bufmgr = Arc::new(BufferPoolManager::new(
+            Box::new(store),
+            BufferPool::new(8, PAGE_SIZE),
+        ));
+        bufmgr.set_read_ahead(0);
+        for &page_id in &page_ids {
+            bufmgr.fetch_page_write(page_id).unwrap()[0] = 1;
+        }
+        let before = bufmgr.stats().background_writes;
+
+        let options = BackgroundWriterOptions {
+            interval: Duration::from_millis(20),
+            max_pages: 2,
+        };
+        let started = Instant::now();
+        let writer = BackgroundWriter::start(&bufmgr, options);
+        while bufmgr.stats().background_writes - before < page_ids.len() as u64 {
+            assert!(started.elapsed() < Duration::from_secs(10));
+            thread::sleep(Duration::from_millis(1));
+        }
+        // 一回に 2 ページまでなので、6 ページ書き込むには少なくとも 3 回分待っている
+        assert!(started.elapsed() >= options.interval * 3);
+        for &page_id in &page_ids {
+            assert!(!bufmgr.fetch_page(page_id).unwrap().is_dirty());
+            bufmgr.unpin_page(page_id, false).unwrap();
+        }
+
+        // stop() から戻ればスレッドは終わっていて、弱い参照も残っていない
+        writer.stop();
+        assert_eq!(Arc::weak_count(&bufmgr), 0);
+        let written = bufmgr.stats().background_writes;
+        bufmgr.fetch_page_write(page_ids[0]).unwrap()[0] = 2;
+        thread::sleep(options.interval * 3);
+        assert_eq!(bufmgr.stats().background_writes, written);
+        assert!(bufmgr.fetch_page(page_ids[0]).unwrap().is_dirty());
+        bufmgr.unpin_page(page_ids[0], false).unwrap();
+    }
+}
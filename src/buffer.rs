@@ -0,0 +1,2229 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{Error, ErrorKind};
+use std::ops::{Deref, DerefMut, Index, IndexMut};
+use std::panic::Location;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{
+    Condvar, Mutex, MutexGuard, OnceLock, RwLock, RwLockReadGuard, RwLockWriteGuard, TryLockError,
+};
+
+use crate::disk::{usable_page_size, DurabilityMode, PageId};
+use crate::replacer::{ReplacementPolicy, Replacer};
+use crate::stats::{BufferPoolCounters, BufferPoolStats};
+use crate::store::{PageStore, ReferenceRewriter, Relocations};
+use crate::strategy::{AccessStrategy, AccessStrategyKind};
+use crate::MyError;
+
+// 連続したアクセスを検出したときに先読みするページ数の既定値
+pub const DEFAULT_READ_AHEAD_PAGES: usize = 8;
+
+pub type Page = Box<[u8]>;
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BufferId(pub usize);
+// ページの内容は read() / write() でラッチを取って読み書きする
+// fetch_page / create_page でピン留めされ、unpin_page を呼ぶまで追い出されない
+// ピンを外した後のバッファには別のページが置かれうるので、ラッチはピン留めしている間だけ取ること
+pub struct Buffer {
+    page_id: AtomicU64,
+    page: RwLock<Page>,
+    is_dirty: AtomicBool,
+    // 変更済みの印を付けるたびに増やす
+    version: AtomicU64,
+    // ディスクに書き込んだ内容の version
+    // 書き込む間はロックしておき、同じバッファの古い内容が新しい内容を追い越して書き込まれないようにする
+    flushed_version: Mutex<u64>,
+}
+impl Buffer {
+    // ページの領域はプールに加えるときに確保する
+    fn new() -> Self {
+        Self {
+            page_id: AtomicU64::new(PageId::INVALID_PAGE_ID.to_u64()),
+            page: RwLock::new(Page::default()),
+            is_dirty: AtomicBool::new(false),
+            version: AtomicU64::new(0),
+            flushed_version: Mutex::new(0),
+        }
+    }
+
+    // 置かれているページ (どのページも置かれていなければ INVALID_PAGE_ID)
+    pub fn page_id(&self) -> PageId {
+        PageId(self.page_id.load(Ordering::SeqCst))
+    }
+
+    fn set_page_id(&self, page_id: PageId) {
+        self.page_id.store(page_id.to_u64(), Ordering::SeqCst);
+    }
+
+    pub fn read(&self) -> ReadLatch<'_> {
+        ReadLatch {
+            page: self.page.read().unwrap(),
+        }
+    }
+
+    pub fn write(&self) -> WriteLatch<'_> {
+        WriteLatch {
+            page: self.page.write().unwrap(),
+            buffer: self,
+        }
+    }
+
+    pub fn is_dirty(&self) -> bool {
+        self.is_dirty.load(Ordering::SeqCst)
+    }
+
+    fn mark_dirty(&self) {
+        self.version.fetch_add(1, Ordering::SeqCst);
+        self.is_dirty.store(true, Ordering::SeqCst);
+    }
+}
+
+// 書き込むために複製した、変更済みのバッファの内容
+struct DirtyCopy<'a> {
+    buffer: &'a Buffer,
+    version: u64,
+    page: Page,
+}
+
+impl<'a> DirtyCopy<'a> {
+    // 変更されていれば、ページの内容を複製して変更済みの印を外す
+    // 複製した後に書き換えられた場合は、書き換えた側が改めて印を付ける
+    // ラッチは待たずに取り、他で (呼び出し側自身も含めて) 持たれていれば変更済みのまま残して None を返す
+    fn take(buffer: &'a Buffer) -> Option<Self> {
+        if !buffer.is_dirty() {
+            return None;
+        }
+        let page = match buffer.page.try_read() {
+            Ok(page) => page,
+            Err(TryLockError::WouldBlock) => return None,
+            Err(TryLockError::Poisoned(e)) => panic!("{}", e),
+        };
+        if !buffer.is_dirty.swap(false, Ordering::SeqCst) {
+            return None;
+        }
+        Some(Self {
+            buffer,
+            version: buffer.version.load(Ordering::SeqCst),
+            page: page.clone(),
+        })
+    }
+}
+
+// 読み出し用のラッチを持つ間だけページの内容を参照できる
+// 末尾のチェックサムの領域は含まない
+pub struct ReadLatch<'a> {
+    page: RwLockReadGuard<'a, Page>,
+}
+
+impl Deref for ReadLatch<'_> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.page[..usable_page_size(self.page.len())]
+    }
+}
+
+// 書き込み用のラッチを持つ間だけページの内容を変更できる (末尾のチェックサムの領域は含まない)
+// 可変参照を取り出した時点で変更済みの印を付けるので、呼び出し側で is_dirty を立てる必要はない
+pub struct WriteLatch<'a> {
+    page: RwLockWriteGuard<'a, Page>,
+    buffer: &'a Buffer,
+}
+
+impl Deref for WriteLatch<'_> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.page[..usable_page_size(self.page.len())]
+    }
+}
+
+impl DerefMut for WriteLatch<'_> {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        self.buffer.mark_dirty();
+        let usable = usable_page_size(self.page.len());
+        &mut self.page[..usable]
+    }
+}
+
+// fetch_page_read が返す、ピンと読み出し用のラッチを持つガード
+// 破棄するとラッチを離してからピンを外す
+pub struct ReadPageGuard<'a> {
+    bufmgr: &'a BufferPoolManager,
+    page_id: PageId,
+    latch: Option<ReadLatch<'a>>,
+}
+
+impl ReadPageGuard<'_> {
+    pub fn page_id(&self) -> PageId {
+        self.page_id
+    }
+}
+
+impl Deref for ReadPageGuard<'_> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        self.latch.as_ref().unwrap()
+    }
+}
+
+impl Drop for ReadPageGuard<'_> {
+    fn drop(&mut self) {
+        self.latch.take();
+        // ガードがピンを持っているので外せないことはない
+        let _ = self.bufmgr.unpin_page(self.page_id, false);
+    }
+}
+
+// fetch_page_write / create_page_write が返す、ピンと書き込み用のラッチを持つガード
+// 破棄するとラッチを離してからピンを外し、可変参照を取り出していれば変更済みの印を付ける
+pub struct WritePageGuard<'a> {
+    bufmgr: &'a BufferPoolManager,
+    page_id: PageId,
+    latch: Option<WriteLatch<'a>>,
+    is_dirty: bool,
+}
+
+impl WritePageGuard<'_> {
+    pub fn page_id(&self) -> PageId {
+        self.page_id
+    }
+}
+
+impl Deref for WritePageGuard<'_> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        self.latch.as_ref().unwrap()
+    }
+}
+
+impl DerefMut for WritePageGuard<'_> {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        self.is_dirty = true;
+        self.latch.as_mut().unwrap()
+    }
+}
+
+impl Drop for WritePageGuard<'_> {
+    fn drop(&mut self) {
+        self.latch.take();
+        let _ = self.bufmgr.unpin_page(self.page_id, self.is_dirty);
+    }
+}
+
+// バッファを置く領域
+// 一度作ったバッファは動かさないので、ページテーブルのラッチを離した後も &self の寿命で参照できる
+// i 番目のチャンクに 2^i 個のバッファを置き、プールを大きくするときは足りない分のチャンクだけを足す
+struct BufferArena {
+    chunks: [OnceLock<Box<[Buffer]>>; usize::BITS as usize],
+}
+
+impl BufferArena {
+    fn new() -> Self {
+        Self {
+            chunks: std::array::from_fn(|_| OnceLock::new()),
+        }
+    }
+
+    fn locate(buffer_id: BufferId) -> (usize, usize) {
+        let n = buffer_id.0 + 1;
+        let chunk = (usize::BITS - 1 - n.leading_zeros()) as usize;
+        (chunk, n - (1 << chunk))
+    }
+
+    fn get(&self, buffer_id: BufferId) -> &Buffer {
+        let (chunk, index) = Self::locate(buffer_id);
+        &self.chunks[chunk]
+            .get()
+            .expect("buffer is outside the buffer pool")[index]
+    }
+
+    // バッファを用意し (まだなければチャンクを足す)、ページの領域を確保する
+    fn allocate(&self, buffer_id: BufferId, page_size: usize) {
+        let (last, _) = Self::locate(buffer_id);
+        for (i, chunk) in self.chunks[..=last].iter().enumerate() {
+            chunk.get_or_init(|| (0..1usize << i).map(|_| Buffer::new()).collect());
+        }
+        *self.get(buffer_id).page.write().unwrap() = vec![0u8; page_size].into_boxed_slice();
+    }
+
+    // プールから外したバッファのページの領域を解放する
+    fn deallocate(&self, buffer_id: BufferId) {
+        let buffer = self.get(buffer_id);
+        *buffer.page.write().unwrap() = Page::default();
+        buffer.set_page_id(PageId::INVALID_PAGE_ID);
+        buffer.is_dirty.store(false, Ordering::SeqCst);
+    }
+}
+
+pub struct Frame {
+    // 置かれているページ (どのページも置かれていなければ None)
+    page_id: Option<PageId>,
+    // AccessStrategy のリングで読み込まれ、まだリングの外から参照されていない
+    in_ring: bool,
+    // fetch_page / create_page で増やし、unpin_page で減らす
+    pin_count: usize,
+    // バッファプールの中で読み書きする間だけ付けるピン (unpin_page では外せない)
+    io_pins: usize,
+    // ピン留めした呼び出し元 (追跡が有効な間だけ記録する)
+    pinned_at: Vec<&'static Location<'static>>,
+    // ディスクから読み込んでいる最中 (読み込んでいるスレッドが io_pins でピン留めしている)
+    loading: bool,
+    // resize で外したバッファ (ページの領域は解放してあり、追い出す候補にもならない)
+    retired: bool,
+}
+impl Frame {
+    fn new() -> Self {
+        Self {
+            page_id: None,
+            in_ring: false,
+            pin_count: 0,
+            io_pins: 0,
+            pinned_at: vec![],
+            loading: false,
+            retired: false,
+        }
+    }
+
+    fn is_pinned(&self) -> bool {
+        self.pin_count > 0 || self.io_pins > 0
+    }
+
+    fn is_evictable(&self) -> bool {
+        !self.is_pinned() && !self.retired
+    }
+}
+
+// ピン留めされたまま残っているページ (pin_leaks で調べる)
+#[derive(Debug, Clone)]
+pub struct PinnedPage {
+    pub page_id: PageId,
+    pub pin_count: usize,
+    // ピン留めした呼び出し元 (追跡を有効にする前のピンは含まない)
+    pub pinned_at: Vec<&'static Location<'static>>,
+}
+
+impl fmt::Display for PinnedPage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "page {} is pinned {} time(s)",
+            self.page_id.to_u64(),
+            self.pin_count
+        )?;
+        for location in &self.pinned_at {
+            write!(f, "\n    pinned at {}", location)?;
+        }
+        Ok(())
+    }
+}
+pub struct BufferPool {
+    frames: Vec<Frame>,
+    // どのページも置かれていないバッファ。追い出す前にこちらから使う
+    free_frames: Vec<BufferId>,
+    // resize で外したバッファ。大きくするときはこちらから使い直す
+    retired_frames: Vec<BufferId>,
+    replacer: Box<dyn Replacer>,
+    page_size: usize,
+}
+
+impl BufferPool {
+    pub fn new(pool_size: usize, page_size: usize) -> Self {
+        Self::with_policy(pool_size, page_size, ReplacementPolicy::default())
+    }
+
+    pub fn with_policy(pool_size: usize, page_size: usize, policy: ReplacementPolicy) -> Self {
+        Self::with_replacer(pool_size, page_size, policy.build(pool_size))
+    }
+
+    pub fn with_replacer(pool_size: usize, page_size: usize, replacer: Box<dyn Replacer>) -> Self {
+        let mut frames = vec![];
+        frames.resize_with(pool_size, Frame::new);
+        // 先頭のバッファから使うよう、逆順に積んでおく
+        let free_frames = (0..pool_size).rev().map(BufferId).collect();
+        Self {
+            frames,
+            free_frames,
+            retired_frames: vec![],
+            replacer,
+            page_size,
+        }
+    }
+
+    pub fn page_size(&self) -> usize {
+        self.page_size
+    }
+
+    // ページを置くバッファを選ぶ。空いているバッファがなければ追い出すバッファを選ぶ
+    fn allocate_frame(&mut self) -> Option<BufferId> {
+        self.free_frames.pop().or_else(|| self.evict())
+    }
+
+    fn evict(&mut self) -> Option<BufferId> {
+        let frames = &self.frames;
+        self.replacer
+            .victim(&|buffer_id| frames[buffer_id.0].is_evictable())
+    }
+
+    fn record_access(&mut self, buffer_id: BufferId, page_id: PageId) {
+        self.replacer.record_access(buffer_id, page_id);
+    }
+
+    #[track_caller]
+    fn pin(&mut self, buffer_id: BufferId, track: bool) {
+        let frame = &mut self[buffer_id];
+        frame.pin_count += 1;
+        if track {
+            frame.pinned_at.push(Location::caller());
+        }
+    }
+
+    // 使っているバッファの数 (外したバッファは含まない)
+    fn size(&self) -> usize {
+        self.frames.len() - self.retired_frames.len()
+    }
+
+    // バッファを一つ足して空いているバッファに加える。外したバッファがあればそれを使い直す
+    fn add_frame(&mut self) -> BufferId {
+        let buffer_id = match self.retired_frames.pop() {
+            Some(buffer_id) => {
+                self[buffer_id].retired = false;
+                buffer_id
+            }
+            None => {
+                self.frames.push(Frame::new());
+                self.replacer.resize(self.frames.len());
+                BufferId(self.frames.len() - 1)
+            }
+        };
+        self.free_frames.push(buffer_id);
+        buffer_id
+    }
+
+    // どのページも置かれておらず、空いているバッファの一覧からも外したバッファを使わないようにする
+    fn retire_frame(&mut self, buffer_id: BufferId) {
+        self[buffer_id].retired = true;
+        self.replacer.remove(buffer_id);
+        self.retired_frames.push(buffer_id);
+    }
+}
+
+impl Index<BufferId> for BufferPool {
+    type Output = Frame;
+
+    fn index(&self, index: BufferId) -> &Self::Output {
+        &self.frames[index.0]
+    }
+}
+
+impl IndexMut<BufferId> for BufferPool {
+    fn index_mut(&mut self, index: BufferId) -> &mut Self::Output {
+        &mut self.frames[index.0]
+    }
+}
+
+// 複数のスレッドから Arc で共有して使う
+// ページテーブルとクロックの状態は一つのラッチで守り、ページの内容はバッファごとのラッチで守る
+// ディスク I/O の間はページテーブルのラッチを離し、他のページへのアクセスを止めない
+// 読み込み中のバッファはピン留めして loading を立てておき、同じページを求めるスレッドは io_done で待つ
+// compact だけは移動の途中を見せないよう、最後までページテーブルのラッチを持つ
+pub struct BufferPoolManager {
+    disk: Box<dyn PageStore>,
+    buffers: BufferArena,
+    state: Mutex<PoolState>,
+    io_done: Condvar,
+    counters: BufferPoolCounters,
+}
+
+struct PoolState {
+    pool: BufferPool,
+    page_table: HashMap<PageId, BufferId>,
+    read_ahead_pages: usize,
+    // 直前に fetch_page したページ (連続したアクセスの検出に使う)
+    last_page_id: PageId,
+    // バックグラウンドライタが次に見るバッファ
+    writer_cursor: usize,
+    // ピン留めした呼び出し元を記録する (set_pin_tracking)
+    track_pins: bool,
+}
+
+impl BufferPoolManager {
+    pub fn new(disk: Box<dyn PageStore>, pool: BufferPool) -> Self {
+        assert_eq!(
+            disk.page_size(),
+            pool.page_size(),
+            "buffer pool page size must match the database file"
+        );
+        let buffers = BufferArena::new();
+        for buffer_id in (0..pool.size()).map(BufferId) {
+            buffers.allocate(buffer_id, pool.page_size());
+        }
+        let page_table = HashMap::new();
+        Self {
+            disk,
+            buffers,
+            state: Mutex::new(PoolState {
+                pool,
+                page_table,
+                read_ahead_pages: DEFAULT_READ_AHEAD_PAGES,
+                last_page_id: PageId::INVALID_PAGE_ID,
+                writer_cursor: 0,
+                track_pins: false,
+            }),
+            io_done: Condvar::new(),
+            counters: BufferPoolCounters::default(),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, PoolState> {
+        self.state.lock().unwrap()
+    }
+
+    fn buffer(&self, buffer_id: BufferId) -> &Buffer {
+        self.buffers.get(buffer_id)
+    }
+
+    // ラッチやガードから参照できるページの大きさ (末尾のチェックサムの領域を除く)
+    pub fn usable_page_size(&self) -> usize {
+        self.disk.usable_page_size()
+    }
+
+    // 連続したページへのアクセスを検出したときに先読みするページ数 (0 で先読みしない)
+    pub fn set_read_ahead(&self, pages: usize) {
+        self.lock().read_ahead_pages = pages;
+    }
+
+    // ピン留めした呼び出し元を記録するかどうか (デバッグ用)
+    // 有効な間は、空いているバッファがなくなったときと破棄するときに、ピン留めされたままのページを標準エラー出力に出す
+    pub fn set_pin_tracking(&self, enabled: bool) {
+        let mut state = self.lock();
+        state.track_pins = enabled;
+        if !enabled {
+            for frame in state.pool.frames.iter_mut() {
+                frame.pinned_at.clear();
+            }
+        }
+    }
+
+    // 返したバッファはピン留めされるので、使い終わったら unpin_page を呼ぶこと
+    #[track_caller]
+    pub fn fetch_page(&self, page_id: PageId) -> Result<&Buffer, MyError> {
+        let buffer_id = self.fetch_page_inner(page_id, None)?;
+        Ok(self.buffer(buffer_id))
+    }
+
+    // ピンと読み出し用のラッチを持つガードを返す (ガードを破棄するとピンが外れる)
+    #[track_caller]
+    pub fn fetch_page_read(&self, page_id: PageId) -> Result<ReadPageGuard<'_>, MyError> {
+        let buffer_id = self.fetch_page_inner(page_id, None)?;
+        Ok(ReadPageGuard {
+            bufmgr: self,
+            page_id,
+            latch: Some(self.buffer(buffer_id).read()),
+        })
+    }
+
+    // ピンと書き込み用のラッチを持つガードを返す (ガードを破棄するとピンが外れる)
+    #[track_caller]
+    pub fn fetch_page_write(&self, page_id: PageId) -> Result<WritePageGuard<'_>, MyError> {
+        let buffer_id = self.fetch_page_inner(page_id, None)?;
+        Ok(self.write_guard(buffer_id, page_id))
+    }
+
+    fn write_guard(&self, buffer_id: BufferId, page_id: PageId) -> WritePageGuard<'_> {
+        WritePageGuard {
+            bufmgr: self,
+            page_id,
+            latch: Some(self.buffer(buffer_id).write()),
+            is_dirty: false,
+        }
+    }
+
+    // fetch_page / create_page で付けたピンを一つ外す。is_dirty が true なら変更済みの印を付ける
+    // ピン留めされていないページを指定した場合は PageNotPinned を返す
+    // (書き込み中などにバッファプールの中で付けたピンは数えない)
+    // バッファのラッチは先に離しておくこと
+    pub fn unpin_page(&self, page_id: PageId, is_dirty: bool) -> Result<(), MyError> {
+        let mut state = self.lock();
+        let Some(&buffer_id) = state.page_table.get(&page_id) else {
+            return Err(MyError::PageNotPinned(page_id));
+        };
+        let frame = &mut state.pool[buffer_id];
+        if frame.pin_count == 0 {
+            return Err(MyError::PageNotPinned(page_id));
+        }
+        frame.pin_count -= 1;
+        // どのピンを外したかは分からないので、新しい方から忘れる
+        frame.pinned_at.truncate(frame.pin_count);
+        if is_dirty {
+            self.buffer(buffer_id).mark_dirty();
+        }
+        Ok(())
+    }
+
+    // ページを解放して、格納先の空きページに戻す
+    // バッファプールにあれば、変更されていても書き込まずに捨てる
+    // ピン留めされていれば PagePinned を返し、何もしない
+    pub fn delete_page(&self, page_id: PageId) -> Result<(), MyError> {
+        {
+            let mut state = self.lock();
+            if let Some(&buffer_id) = state.page_table.get(&page_id) {
+                if state.pool[buffer_id].is_pinned() {
+                    return Err(MyError::PagePinned(page_id));
+                }
+                state.page_table.remove(&page_id);
+                self.release_frame(&mut state, buffer_id);
+            }
+        }
+        self.disk.deallocate_page(page_id)
+    }
+
+    // ピン留めされたままのページを返す
+    pub fn pin_leaks(&self) -> Vec<PinnedPage> {
+        Self::collect_pin_leaks(&self.lock())
+    }
+
+    fn collect_pin_leaks(state: &PoolState) -> Vec<PinnedPage> {
+        state
+            .pool
+            .frames
+            .iter()
+            .filter(|frame| frame.pin_count > 0)
+            .filter_map(|frame| {
+                Some(PinnedPage {
+                    page_id: frame.page_id?,
+                    pin_count: frame.pin_count,
+                    pinned_at: frame.pinned_at.clone(),
+                })
+            })
+            .collect()
+    }
+
+    fn report_pin_leaks(state: &PoolState, reason: &str) {
+        let leaks = Self::collect_pin_leaks(state);
+        if leaks.is_empty() {
+            return;
+        }
+        eprintln!("{}: {} page(s) still pinned", reason, leaks.len());
+        for leak in leaks {
+            eprintln!("  {}", leak);
+        }
+    }
+
+    // 大量のページを一度ずつ読む処理のためのリングを作る
+    pub fn access_strategy(&self, kind: AccessStrategyKind) -> AccessStrategy {
+        AccessStrategy::new(kind, self.lock().pool.size())
+    }
+
+    // ページが共有のバッファプールになければ、strategy のリングのバッファに読み込む
+    // リングで読み込んだページは追い出す候補として最初に選ばれ、他のページを押し出さない
+    #[track_caller]
+    pub fn fetch_page_with(
+        &self,
+        page_id: PageId,
+        strategy: &mut AccessStrategy,
+    ) -> Result<&Buffer, MyError> {
+        let buffer_id = self.fetch_page_inner(page_id, Some(strategy))?;
+        Ok(self.buffer(buffer_id))
+    }
+
+    #[track_caller]
+    fn fetch_page_inner(
+        &self,
+        page_id: PageId,
+        mut strategy: Option<&mut AccessStrategy>,
+    ) -> Result<BufferId, MyError> {
+        let mut state = self.lock();
+        let sequential = state
+            .last_page_id
+            .valid()
+            .is_some_and(|last_page_id| last_page_id.to_u64() + 1 == page_id.to_u64());
+        state.last_page_id = page_id;
+        let track = state.track_pins;
+        let buffer_id = loop {
+            // ページがバッファプールにある場合は返す
+            if let Some(&buffer_id) = state.page_table.get(&page_id) {
+                if strategy.is_none() {
+                    state.pool[buffer_id].in_ring = false;
+                }
+                if state.pool[buffer_id].loading {
+                    // 他のスレッドが読み込んでいる間はラッチを離して待つ
+                    state.pool[buffer_id].io_pins += 1;
+                    state = self
+                        .io_done
+                        .wait_while(state, |state| state.pool[buffer_id].loading)
+                        .unwrap();
+                    self.unpin_frame(&mut state, buffer_id);
+                    if state.pool[buffer_id].page_id != Some(page_id) {
+                        // 読み込みに失敗したので、改めて読み込む
+                        continue;
+                    }
+                }
+                state.pool.pin(buffer_id, track);
+                state.pool.record_access(buffer_id, page_id);
+                self.counters.hits.add(1);
+                return Ok(buffer_id);
+            }
+            // 捨てる (これから読み込むページを格納する) バッファを選ぶ
+            let (next, result) = self.take_frame(state, strategy.as_deref_mut());
+            state = next;
+            let buffer_id = match result {
+                Ok(buffer_id) => buffer_id,
+                Err(e) => {
+                    self.counters.misses.add(1);
+                    return Err(e);
+                }
+            };
+            // 変更されたページを書き戻している間に、他のスレッドが読み込んだかもしれない
+            if state.page_table.contains_key(&page_id) {
+                self.release_frame(&mut state, buffer_id);
+                continue;
+            }
+            break buffer_id;
+        };
+        self.counters.misses.add(1);
+        // 読み込み中の印を付けてからページテーブルのラッチを離し、ページを読み出す
+        self.start_loading(&mut state, buffer_id, page_id);
+        state.pool[buffer_id].io_pins += 1;
+        drop(state);
+        let buffer = self.buffer(buffer_id);
+        let result = self
+            .disk
+            .read_page_data(page_id, &mut buffer.page.write().unwrap());
+        let mut state = self.lock();
+        self.finish_loading(&mut state, buffer_id, result.is_ok());
+        self.unpin_frame(&mut state, buffer_id);
+        result?;
+        state.pool.pin(buffer_id, track);
+        if let Some(strategy) = strategy {
+            // 参照を記録しないので、リングのページは追い出す候補として最初に選ばれる
+            state.pool[buffer_id].in_ring = true;
+            strategy.ring[strategy.current] = Some((buffer_id, page_id));
+            return Ok(buffer_id);
+        }
+        state.pool.record_access(buffer_id, page_id);
+        // 順に読み進めているようなら続きのページを先読みする
+        // 他のページを追い出し過ぎないよう、プールの 1/4 までに抑える
+        // 先読みはヒントなので、失敗しても次に fetch_page したときに改めて読めばよい
+        let read_ahead_pages = state.read_ahead_pages.min(state.pool.size() / 4);
+        if sequential && read_ahead_pages > 0 {
+            let _ = self.read_ahead(state, PageId(page_id.to_u64() + 1), read_ahead_pages);
+        }
+        Ok(buffer_id)
+    }
+
+    // バッファにページを置き、読み込み中の印を付ける
+    // 読み込み終わるまで他のスレッドは同じページを読み込まず、finish_loading を待つ
+    fn start_loading(&self, state: &mut PoolState, buffer_id: BufferId, page_id: PageId) {
+        let frame = &mut state.pool[buffer_id];
+        frame.page_id = Some(page_id);
+        frame.loading = true;
+        self.buffer(buffer_id).set_page_id(page_id);
+        state.page_table.insert(page_id, buffer_id);
+    }
+
+    // 読み込み中の印を外し、待っているスレッドを起こす
+    // 読み込めなかった場合はページテーブルから外す (バッファはピンがすべて外れたときに空きに戻る)
+    fn finish_loading(&self, state: &mut PoolState, buffer_id: BufferId, loaded: bool) {
+        let frame = &mut state.pool[buffer_id];
+        frame.loading = false;
+        if !loaded {
+            if let Some(page_id) = frame.page_id.take() {
+                state.page_table.remove(&page_id);
+            }
+            self.buffer(buffer_id).set_page_id(PageId::INVALID_PAGE_ID);
+        }
+        self.io_done.notify_all();
+    }
+
+    // バッファプールの中で付けたピン (io_pins) を外す
+    // どのページも置かれていないバッファは、ピンがすべて外れたら空きに戻す
+    fn unpin_frame(&self, state: &mut PoolState, buffer_id: BufferId) {
+        let frame = &mut state.pool[buffer_id];
+        frame.io_pins -= 1;
+        if !frame.is_pinned() && frame.page_id.is_none() {
+            self.release_frame(state, buffer_id);
+        }
+    }
+
+    // 新しいページを割り当て、ディスクからは読まずにゼロで埋めたバッファを返す
+    // fetch_page と同じく、返したバッファはピン留めされる
+    #[track_caller]
+    pub fn create_page(&self) -> Result<&Buffer, MyError> {
+        let buffer_id = self.create_page_inner(None)?;
+        Ok(self.buffer(buffer_id))
+    }
+
+    // create_page と同じだが、ピンと書き込み用のラッチを持つガードを返す
+    #[track_caller]
+    pub fn create_page_write(&self) -> Result<WritePageGuard<'_>, MyError> {
+        let buffer_id = self.create_page_inner(None)?;
+        let page_id = self.buffer(buffer_id).page_id();
+        Ok(self.write_guard(buffer_id, page_id))
+    }
+
+    // 新しいページを strategy のリングのバッファに作る (一括ロード向け)
+    #[track_caller]
+    pub fn create_page_with(&self, strategy: &mut AccessStrategy) -> Result<&Buffer, MyError> {
+        let buffer_id = self.create_page_inner(Some(strategy))?;
+        Ok(self.buffer(buffer_id))
+    }
+
+    #[track_caller]
+    fn create_page_inner(
+        &self,
+        mut strategy: Option<&mut AccessStrategy>,
+    ) -> Result<BufferId, MyError> {
+        // 空き領域の管理は格納先でロックするので、ページテーブルのラッチを取る前に割り当てる
+        let page_id = self.disk.allocate_page()?;
+        let (mut state, result) = self.take_frame(self.lock(), strategy.as_deref_mut());
+        let buffer_id = match result {
+            Ok(buffer_id) => buffer_id,
+            Err(e) => {
+                drop(state);
+                // 使われないページが残らないよう返しておく
+                let _ = self.disk.deallocate_page(page_id);
+                return Err(e);
+            }
+        };
+        // 解放される前の内容が残っていれば捨てる (割り当てたときに格納先ではゼロに戻っている)
+        if let Some(&cached_id) = state.page_table.get(&page_id) {
+            if state.pool[cached_id].is_pinned() {
+                self.release_frame(&mut state, buffer_id);
+                drop(state);
+                let _ = self.disk.deallocate_page(page_id);
+                return Err(MyError::PagePinned(page_id));
+            }
+            state.page_table.remove(&page_id);
+            self.release_frame(&mut state, cached_id);
+        }
+        let buffer = self.buffer(buffer_id);
+        buffer.set_page_id(page_id);
+        // どこからもピン留めされていないバッファのラッチは誰も持っていないので、すぐに取れる
+        buffer.page.write().unwrap().fill(0);
+        // まだディスクに書かれていないので、追い出すときに必ず書き込む
+        buffer.mark_dirty();
+        state.pool[buffer_id].page_id = Some(page_id);
+        let track = state.track_pins;
+        state.pool.pin(buffer_id, track);
+        state.page_table.insert(page_id, buffer_id);
+        match strategy {
+            Some(strategy) => {
+                state.pool[buffer_id].in_ring = true;
+                strategy.ring[strategy.current] = Some((buffer_id, page_id));
+            }
+            None => state.pool.record_access(buffer_id, page_id),
+        }
+        Ok(buffer_id)
+    }
+
+    // page_id から count ページのうち、まだ読み込まれていないものを先に読み込んでおく
+    // 割り当てられていないページや解放済みのページに当たったら、その手前までにする
+    // 読み込んだページ数を返す
+    pub fn prefetch(&self, page_id: PageId, count: usize) -> Result<usize, MyError> {
+        self.read_ahead(self.lock(), page_id, count)
+    }
+
+    // fetch_page / create_page で使うバッファを選ぶ
+    // 空いているバッファがなく、ピン留めを追跡していれば、ピン留めされたままのページを標準エラー出力に出す
+    fn take_frame<'a>(
+        &'a self,
+        state: MutexGuard<'a, PoolState>,
+        strategy: Option<&mut AccessStrategy>,
+    ) -> (MutexGuard<'a, PoolState>, Result<BufferId, MyError>) {
+        let (state, result) = self.take_victim(state, strategy);
+        if matches!(result, Err(MyError::NoFreeBuffer)) && state.track_pins {
+            Self::report_pin_leaks(&state, "no free buffer in buffer pool");
+        }
+        (state, result)
+    }
+
+    // ページを置くバッファを選び、ページテーブルから外して返す
+    // 空いているバッファがなければ追い出すバッファを選ぶ
+    // 変更されていれば、ピン留めしてページテーブルのラッチを離してから書き戻す
+    // 書き込んでいる間に他のスレッドがピン留めしたか、また変更された場合は別のバッファを選び直す
+    fn take_victim<'a>(
+        &'a self,
+        mut state: MutexGuard<'a, PoolState>,
+        mut strategy: Option<&mut AccessStrategy>,
+    ) -> (MutexGuard<'a, PoolState>, Result<BufferId, MyError>) {
+        loop {
+            let buffer_id = match strategy.as_deref_mut() {
+                Some(strategy) => Self::take_ring_victim(&mut state, strategy),
+                None => state.pool.allocate_frame(),
+            };
+            let Some(buffer_id) = buffer_id else {
+                return (state, Err(MyError::NoFreeBuffer));
+            };
+            let frame = &mut state.pool[buffer_id];
+            frame.in_ring = false;
+            let Some(evict_page_id) = frame.page_id else {
+                return (state, Ok(buffer_id));
+            };
+            let buffer = self.buffer(buffer_id);
+            // ピン留めされていないバッファのラッチは誰も持っていないはずなので、内容はページテーブルのラッチを持ったまま複製する
+            let copy = DirtyCopy::take(buffer);
+            if copy.is_none() && !buffer.is_dirty() {
+                self.evict_frame(&mut state, buffer_id);
+                return (state, Ok(buffer_id));
+            }
+            let Some(copy) = copy else {
+                // ピン留めせずにラッチを持たれていて複製できないので、候補に戻して別のバッファを選ぶ
+                state.pool.record_access(buffer_id, evict_page_id);
+                continue;
+            };
+            state.pool[buffer_id].io_pins += 1;
+            drop(state);
+            let result = self.write_copies(vec![copy]);
+            state = self.lock();
+            state.pool[buffer_id].io_pins -= 1;
+            if let Ok(written) = result {
+                self.counters.dirty_writebacks.add(written as u64);
+                if !state.pool[buffer_id].is_pinned() && !buffer.is_dirty() {
+                    self.evict_frame(&mut state, buffer_id);
+                    return (state, Ok(buffer_id));
+                }
+            }
+            // 追い出せなかったので、追い出す候補に戻す
+            state.pool.record_access(buffer_id, evict_page_id);
+            if let Err(e) = result {
+                return (state, Err(e));
+            }
+        }
+    }
+
+    // リングの次のバッファを使う
+    // 前回そこに読み込んだページがリングの外から参照されておらず、ピン留めもされていなければ追い出して使い回す
+    // そうでなければ共有のバッファプールから新しく選んでリングに加える
+    fn take_ring_victim(state: &mut PoolState, strategy: &mut AccessStrategy) -> Option<BufferId> {
+        strategy.current = (strategy.current + 1) % strategy.ring.len();
+        if let Some((buffer_id, page_id)) = strategy.ring[strategy.current] {
+            let frame = &state.pool[buffer_id];
+            let reusable = frame.in_ring && frame.page_id == Some(page_id) && frame.is_evictable();
+            if reusable {
+                state.pool.replacer.remove(buffer_id);
+                return Some(buffer_id);
+            }
+        }
+        strategy.ring[strategy.current] = None;
+        state.pool.allocate_frame()
+    }
+
+    // ピン留めされておらず、変更もされていないバッファからページを追い出し、ページテーブルから外す
+    fn evict_frame(&self, state: &mut PoolState, buffer_id: BufferId) {
+        let frame = &mut state.pool[buffer_id];
+        frame.in_ring = false;
+        let Some(evict_page_id) = frame.page_id.take() else {
+            return;
+        };
+        self.counters.evictions.add(1);
+        self.buffer(buffer_id).set_page_id(PageId::INVALID_PAGE_ID);
+        state.page_table.remove(&evict_page_id);
+    }
+
+    // ピン留めされていないバッファからページを外し、空いているバッファに戻す
+    // 内容は書き込み済みか、捨ててよいものであること
+    fn release_frame(&self, state: &mut PoolState, buffer_id: BufferId) {
+        let frame = &mut state.pool[buffer_id];
+        frame.page_id = None;
+        frame.in_ring = false;
+        let buffer = self.buffer(buffer_id);
+        buffer.set_page_id(PageId::INVALID_PAGE_ID);
+        buffer.is_dirty.store(false, Ordering::SeqCst);
+        state.pool.replacer.remove(buffer_id);
+        state.pool.free_frames.push(buffer_id);
+    }
+
+    // 空いているバッファか、ピン留めされておらず変更もされていないバッファを選び、ページテーブルから外して返す
+    fn take_clean_frame(&self, state: &mut PoolState) -> Option<BufferId> {
+        if let Some(buffer_id) = state.pool.free_frames.pop() {
+            return Some(buffer_id);
+        }
+        let BufferPool {
+            frames, replacer, ..
+        } = &mut state.pool;
+        let buffer_id = replacer.victim(&|buffer_id| {
+            frames[buffer_id.0].is_evictable() && !self.buffer(buffer_id).is_dirty()
+        })?;
+        self.evict_frame(state, buffer_id);
+        Some(buffer_id)
+    }
+
+    // 連続するページをまとめて一度に読み込む
+    // 読み込むバッファには読み込み中の印を付けておき、読み出す間はページテーブルのラッチを離す
+    fn read_ahead<'a>(
+        &'a self,
+        mut state: MutexGuard<'a, PoolState>,
+        first_page_id: PageId,
+        count: usize,
+    ) -> Result<usize, MyError> {
+        // 既に読み込まれているページは飛ばし、その次の読み込まれているページの手前までを読む
+        let page_ids: Vec<PageId> = (first_page_id.to_u64()
+            ..first_page_id.to_u64().saturating_add(count as u64))
+            .map(PageId)
+            .skip_while(|page_id| state.page_table.contains_key(page_id))
+            .take_while(|page_id| !state.page_table.contains_key(page_id))
+            .collect();
+        // 選んだバッファが再び選ばれないよう、読み終わるまでピン留めしておく
+        // 先読みのために変更されたページを書き戻すことはせず、使えるバッファがなくなればそこまでを読む
+        let mut buffer_ids = vec![];
+        for &page_id in &page_ids {
+            let Some(buffer_id) = self.take_clean_frame(&mut state) else {
+                break;
+            };
+            self.start_loading(&mut state, buffer_id, page_id);
+            state.pool.record_access(buffer_id, page_id);
+            state.pool[buffer_id].io_pins += 1;
+            buffer_ids.push(buffer_id);
+        }
+        let mut result = Ok(0);
+        if !buffer_ids.is_empty() {
+            drop(state);
+            // 読み込み中のバッファのラッチは誰も取らないので、すぐに取れる
+            let mut pages: Vec<_> = buffer_ids
+                .iter()
+                .map(|&buffer_id| self.buffer(buffer_id).page.write().unwrap())
+                .collect();
+            let mut batch: Vec<&mut [u8]> = pages.iter_mut().map(|page| &mut page[..]).collect();
+            result = self.disk.read_pages(page_ids[0], &mut batch);
+            drop(pages);
+            state = self.lock();
+        }
+        let read = *result.as_ref().unwrap_or(&0);
+        for (i, &buffer_id) in buffer_ids.iter().enumerate() {
+            // 読めなかったバッファはピンが外れたときに空きに戻る
+            self.finish_loading(&mut state, buffer_id, i < read);
+            self.unpin_frame(&mut state, buffer_id);
+        }
+        self.counters.prefetched_pages.add(read as u64);
+        result
+    }
+
+    // 条件に合うバッファを書き込む間だけピン留めしておき、書き込んだページ数を返す
+    // 書き込んでいる間に追い出されないようピン留めし、I/O の間はページテーブルのラッチを離す
+    fn write_pinned(
+        &self,
+        select: impl FnOnce(&mut PoolState) -> Vec<BufferId>,
+    ) -> Result<usize, MyError> {
+        let buffer_ids = {
+            let mut state = self.lock();
+            let buffer_ids = select(&mut state);
+            for &buffer_id in &buffer_ids {
+                state.pool[buffer_id].io_pins += 1;
+            }
+            buffer_ids
+        };
+        let result = self.write_buffers(&buffer_ids);
+        let mut state = self.lock();
+        for &buffer_id in &buffer_ids {
+            state.pool[buffer_id].io_pins -= 1;
+        }
+        result
+    }
+
+    // 指定したページが変更されていればディスクに書き込む (バッファプールになければ何もしない)
+    // ラッチを持っている間に呼んだ場合は書き込まずに変更済みのまま残す
+    pub fn flush_page(&self, page_id: PageId) -> Result<(), MyError> {
+        self.write_pinned(|state| {
+            state
+                .page_table
+                .get(&page_id)
+                .copied()
+                .into_iter()
+                .collect()
+        })?;
+        if self.disk.durability() != DurabilityMode::Off {
+            self.disk.sync()?;
+        }
+        Ok(())
+    }
+
+    pub fn flush_all(&self) -> Result<(), MyError> {
+        // 変更されているバッファをすべてディスクに書き込む
+        self.write_pinned(|state| {
+            state
+                .page_table
+                .values()
+                .copied()
+                .filter(|&buffer_id| self.buffer(buffer_id).is_dirty())
+                .collect()
+        })?;
+        // Off の場合は OS に任せ、明示的な sync() を待つ
+        if self.disk.durability() != DurabilityMode::Off {
+            self.disk.sync()?;
+        }
+        Ok(())
+    }
+
+    // ピン留めされていない変更済みのバッファを最大 limit 個書き込み、書き込んだページ数を返す
+    // 前回の続きから順に見ていくので、繰り返し呼ぶとプール全体を一巡する
+    pub(crate) fn write_dirty_buffers(&self, limit: usize) -> Result<usize, MyError> {
+        let written = self.write_pinned(|state| {
+            // 外したバッファはどのページも置かれていないので飛ばされる
+            let pool_size = state.pool.frames.len();
+            let mut buffer_ids = vec![];
+            for _ in 0..pool_size {
+                if buffer_ids.len() >= limit {
+                    break;
+                }
+                let buffer_id = BufferId(state.writer_cursor % pool_size);
+                state.writer_cursor = (buffer_id.0 + 1) % pool_size;
+                let frame = &state.pool[buffer_id];
+                if frame.page_id.is_some()
+                    && !frame.is_pinned()
+                    && self.buffer(buffer_id).is_dirty()
+                {
+                    buffer_ids.push(buffer_id);
+                }
+            }
+            buffer_ids
+        })?;
+        self.counters.background_writes.add(written as u64);
+        Ok(written)
+    }
+
+    // バッファの内容をまとめて書き込み、書き込んだページ数を返す
+    // ラッチは待たずに取るので、呼び出し側がラッチを持っていてもデッドロックしない
+    // ただしラッチを持たれているバッファは書き込まず、変更済みのまま残す
+    fn write_buffers(&self, buffer_ids: &[BufferId]) -> Result<usize, MyError> {
+        let mut buffer_ids = buffer_ids.to_vec();
+        buffer_ids.sort_by_key(|buffer_id| buffer_id.0);
+        let copies = buffer_ids
+            .iter()
+            .filter_map(|&buffer_id| DirtyCopy::take(self.buffer(buffer_id)))
+            .collect();
+        self.write_copies(copies)
+    }
+
+    // 複製した内容を書き込み、書き込んだページ数を返す (copies はバッファの順に並べておく)
+    // 他のスレッドが同じバッファのより新しい内容を書き込んでいれば、そのバッファは書き込まない
+    // 書き込む間はバッファの順に flushed_version をロックするので、ラッチを待たずにデッドロックもしない
+    fn write_copies(&self, mut copies: Vec<DirtyCopy<'_>>) -> Result<usize, MyError> {
+        let mut flushed: Vec<MutexGuard<'_, u64>> = copies
+            .iter()
+            .map(|copy| copy.buffer.flushed_version.lock().unwrap())
+            .collect();
+        let mut batch: Vec<(PageId, &mut [u8])> = copies
+            .iter_mut()
+            .zip(&flushed)
+            .filter(|(copy, flushed)| copy.version > ***flushed)
+            .map(|(copy, _)| (copy.buffer.page_id(), &mut copy.page[..]))
+            .collect();
+        let written = batch.len();
+        if let Err(e) = self.disk.write_pages(&mut batch) {
+            for copy in &copies {
+                copy.buffer.is_dirty.store(true, Ordering::SeqCst);
+            }
+            return Err(e);
+        }
+        for (copy, flushed) in copies.iter().zip(flushed.iter_mut()) {
+            **flushed = copy.version.max(**flushed);
+        }
+        Ok(written)
+    }
+
+    // バッファプールの大きさを変える
+    // 縮める場合はピン留めされていないバッファを、空いているもの、変更されていないもの、
+    // 変更されたもの (書き込んでから) の順に追い出して外す。外したバッファのページの領域は解放する
+    // 書き込んでいる間はページテーブルのラッチを離すので、その間に変更されたバッファは書き込み直す
+    // ピン留めされているバッファが pool_size より多ければ PagePinned を返す
+    // (書き込みの途中でピン留めが増えた場合は、それまでに外したバッファは外したままになる)
+    pub fn resize(&self, pool_size: usize) -> Result<(), MyError> {
+        if pool_size == 0 {
+            return Err(
+                Error::new(ErrorKind::InvalidInput, "buffer pool size must not be zero").into(),
+            );
+        }
+        loop {
+            {
+                let mut state = self.lock();
+                let state = &mut *state;
+                let page_size = state.pool.page_size();
+                while state.pool.size() < pool_size {
+                    let buffer_id = state.pool.add_frame();
+                    self.buffers.allocate(buffer_id, page_size);
+                }
+                let mut pinned = state.pool.frames.iter().filter(|frame| frame.is_pinned());
+                if let Some(frame) = pinned.nth(pool_size) {
+                    return Err(MyError::PagePinned(
+                        frame.page_id.unwrap_or(PageId::INVALID_PAGE_ID),
+                    ));
+                }
+                while state.pool.size() > pool_size {
+                    let Some(buffer_id) = self.take_clean_frame(state) else {
+                        break;
+                    };
+                    state.pool.retire_frame(buffer_id);
+                    self.buffers.deallocate(buffer_id);
+                }
+                if state.pool.size() == pool_size {
+                    return Ok(());
+                }
+            }
+            // 残りは変更されたバッファなので、ページテーブルのラッチを離して書き込み、改めて追い出す
+            let written = self.write_pinned(|state| {
+                let excess = state.pool.size().saturating_sub(pool_size);
+                (0..state.pool.frames.len())
+                    .map(BufferId)
+                    .filter(|&buffer_id| {
+                        let frame = &state.pool[buffer_id];
+                        frame.page_id.is_some()
+                            && frame.is_evictable()
+                            && self.buffer(buffer_id).is_dirty()
+                    })
+                    .take(excess)
+                    .collect()
+            })?;
+            self.counters.dirty_writebacks.add(written as u64);
+        }
+    }
+
+    pub fn stats(&self) -> BufferPoolStats {
+        self.counters.snapshot(self.disk.stats())
+    }
+
+    // バッファプールとページの格納先の両方の統計を 0 に戻す
+    pub fn reset_stats(&self) {
+        self.counters.reset();
+        self.disk.reset_stats();
+    }
+
+    // 使用中のまま compact する。移動したページのキャッシュが残らないよう、
+    // 書き出した後にバッファプールを空にしてから行う
+    // 途中で他のスレッドがページを読み込まないよう、最後までページテーブルのラッチを持っておく
+    pub fn compact(&self, rewriter: &mut ReferenceRewriter) -> Result<Relocations, MyError> {
+        let mut state = self.lock();
+        let state = &mut *state;
+        for (&page_id, &buffer_id) in state.page_table.iter() {
+            if state.pool[buffer_id].is_pinned() {
+                return Err(MyError::PagePinned(page_id));
+            }
+        }
+        let buffer_ids: Vec<BufferId> = state.page_table.values().copied().collect();
+        self.write_buffers(&buffer_ids)?;
+        if self.disk.durability() != DurabilityMode::Off {
+            self.disk.sync()?;
+        }
+        let buffer_ids: Vec<BufferId> = state.page_table.drain().map(|(_, id)| id).collect();
+        for buffer_id in buffer_ids {
+            self.release_frame(state, buffer_id);
+        }
+        self.disk.compact(rewriter)
+    }
+}
+
+// 変更されたまま残っているページを書き込んでから破棄する
+// エラーを返せないので、失敗した場合は標準エラー出力に出すだけにする
+impl Drop for BufferPoolManager {
+    fn drop(&mut self) {
+        // パニック中は書きかけの内容かもしれないので書き込まない
+        if std::thread::panicking() {
+            return;
+        }
+        if let Err(e) = self.flush_all() {
+            eprintln!("failed to flush the buffer pool: {}", e);
+        }
+        let state = self.lock();
+        if state.track_pins {
+            Self::report_pin_leaks(&state, "buffer pool dropped");
+        }
+    }
+}
+
+pub struct Header {
+    pub prev_page_id: PageId,
+    pub next_page_id: PageId,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disk::{DiskManager, DiskManagerOptions, PAGE_TRAILER_SIZE};
+    use crate::fault::FaultInjector;
+    use crate::stats::DiskStats;
+    use crate::store::MemoryPageStore;
+    use std::sync::{Arc, Condvar};
+    use std::thread;
+
+    const PAGE_SIZE: usize = 4096;
+
+    // ページテーブル、バッファに置かれたページ、空いているバッファの対応が一致していることを確かめる
+    fn assert_consistent(bufmgr: &BufferPoolManager) {
+        let state = bufmgr.lock();
+        for (&page_id, &buffer_id) in state.page_table.iter() {
+            let frame = &state.pool[buffer_id];
+            assert_eq!(frame.page_id, Some(page_id));
+            assert_eq!(bufmgr.buffer(buffer_id).page_id(), page_id);
+            assert!(!state.pool.free_frames.contains(&buffer_id));
+        }
+        for (i, frame) in state.pool.frames.iter().enumerate() {
+            let buffer_id = BufferId(i);
+            assert!(!frame.loading);
+            assert_eq!(frame.io_pins, 0);
+            match frame.page_id {
+                Some(page_id) => assert_eq!(state.page_table.get(&page_id), Some(&buffer_id)),
+                None => {
+                    // 空いているか外したかのどちらか一方にだけ入っている
+                    assert_ne!(
+                        state.pool.free_frames.contains(&buffer_id),
+                        state.pool.retired_frames.contains(&buffer_id)
+                    );
+                    assert_eq!(
+                        frame.retired,
+                        state.pool.retired_frames.contains(&buffer_id)
+                    );
+                    assert!(!bufmgr.buffer(buffer_id).is_dirty());
+                }
+            }
+        }
+        let mut free_frames: Vec<usize> = state.pool.free_frames.iter().map(|id| id.0).collect();
+        free_frames.sort();
+        free_frames.dedup();
+        assert_eq!(free_frames.len(), state.pool.free_frames.len());
+    }
+
+    // 読み込んでピンをすぐに外す
+    fn touch(bufmgr: &BufferPoolManager, page_id: PageId) {
+        bufmgr.fetch_page(page_id).unwrap();
+        bufmgr.unpin_page(page_id, false).unwrap();
+    }
+
+    fn free_frame_count(bufmgr: &BufferPoolManager) -> usize {
+        bufmgr.lock().pool.free_frames.len()
+    }
+
+    fn memory_pool(pool: BufferPool, page_count: usize) -> (BufferPoolManager, Vec<PageId>) {
+        let store = MemoryPageStore::new(PAGE_SIZE).unwrap();
+        let page_ids = (0..page_count)
+            .map(|_| store.allocate_page().unwrap())
+            .collect();
+        let bufmgr = BufferPoolManager::new(Box::new(store), pool);
+        bufmgr.set_read_ahead(0);
+        (bufmgr, page_ids)
+    }
+
+    #[test]
+    fn filling_empty_frames_keeps_page_zero_cached() {
+        let injector = FaultInjector::new();
+        let disk =
+            DiskManager::with_options(injector.file(), DiskManagerOptions::default()).unwrap();
+        let page_ids: Vec<PageId> = (0..3).map(|_| disk.allocate_page().unwrap()).collect();
+        let bufmgr = BufferPoolManager::new(Box::new(disk), BufferPool::new(4, PAGE_SIZE));
+        bufmgr.set_read_ahead(0);
+
+        let page0 = bufmgr.fetch_page(PageId(0)).unwrap();
+        for &page_id in &page_ids {
+            touch(&bufmgr, page_id);
+            assert_consistent(&bufmgr);
+        }
+        let again = bufmgr.fetch_page(PageId(0)).unwrap();
+        assert!(std::ptr::eq(page0, again));
+        assert_eq!(bufmgr.stats().hits, 1);
+        assert_eq!(bufmgr.stats().evictions, 0);
+    }
+
+    #[test]
+    fn free_frames_are_used_before_eviction() {
+        let (bufmgr, page_ids) = memory_pool(BufferPool::new(3, PAGE_SIZE), 4);
+        for &page_id in &page_ids[..3] {
+            touch(&bufmgr, page_id);
+        }
+        assert_eq!(free_frame_count(&bufmgr), 0);
+        assert_eq!(bufmgr.stats().evictions, 0);
+        touch(&bufmgr, page_ids[3]);
+        assert_eq!(bufmgr.stats().evictions, 1);
+        assert_consistent(&bufmgr);
+    }
+
+    #[test]
+    fn failed_read_releases_the_frame() {
+        let (bufmgr, page_ids) = memory_pool(BufferPool::new(2, PAGE_SIZE), 2);
+        touch(&bufmgr, page_ids[0]);
+        // 割り当てられていないページは読めない
+        let missing = PageId(page_ids[1].to_u64() + 100);
+        assert!(bufmgr.fetch_page(missing).is_err());
+        assert_consistent(&bufmgr);
+        assert_eq!(free_frame_count(&bufmgr), 1);
+
+        // 追い出した後に読み出しに失敗しても、古いページの対応は残らない
+        touch(&bufmgr, page_ids[1]);
+        assert!(bufmgr.fetch_page(missing).is_err());
+        assert_consistent(&bufmgr);
+        assert_eq!(free_frame_count(&bufmgr), 1);
+        let cached = bufmgr.lock().page_table.len();
+        assert_eq!(cached, 1);
+    }
+
+    #[test]
+    fn corrupted_page_does_not_disturb_cached_pages() {
+        let injector = FaultInjector::new();
+        let disk =
+            DiskManager::with_options(injector.file(), DiskManagerOptions::default()).unwrap();
+        let page_ids: Vec<PageId> = (0..3).map(|_| disk.allocate_page().unwrap()).collect();
+        for &page_id in &page_ids {
+            let mut data = vec![page_id.to_u64() as u8; PAGE_SIZE];
+            disk.write_page_data(page_id, &mut data).unwrap();
+        }
+        let bufmgr = BufferPoolManager::new(Box::new(disk), BufferPool::new(2, PAGE_SIZE));
+        bufmgr.set_read_ahead(0);
+        touch(&bufmgr, page_ids[0]);
+        touch(&bufmgr, page_ids[1]);
+
+        injector.flip_bit_on_read(page_ids[2].to_u64() * PAGE_SIZE as u64 + 10, 0);
+        assert!(matches!(
+            bufmgr.fetch_page(page_ids[2]),
+            Err(MyError::PageCorrupted { .. })
+        ));
+        assert_consistent(&bufmgr);
+        injector.clear_faults();
+        let page = bufmgr.fetch_page(page_ids[2]).unwrap();
+        assert_eq!(page.read()[0], page_ids[2].to_u64() as u8);
+        assert_consistent(&bufmgr);
+    }
+
+    #[test]
+    fn guards_expose_only_the_usable_page_size() {
+        let injector = FaultInjector::new();
+        let disk =
+            DiskManager::with_options(injector.file(), DiskManagerOptions::default()).unwrap();
+        let page_ids: Vec<PageId> = (0..2).map(|_| disk.allocate_page().unwrap()).collect();
+        let bufmgr = BufferPoolManager::new(Box::new(disk), BufferPool::new(1, PAGE_SIZE));
+        let usable = bufmgr.usable_page_size();
+        assert_eq!(usable, PAGE_SIZE - PAGE_TRAILER_SIZE);
+        {
+            let mut guard = bufmgr.fetch_page_write(page_ids[0]).unwrap();
+            assert_eq!(guard.len(), usable);
+            guard.fill(0xff);
+        }
+        // 追い出して読み直しても、チェックサムの領域は壊れていない
+        touch(&bufmgr, page_ids[1]);
+        let guard = bufmgr.fetch_page_read(page_ids[0]).unwrap();
+        assert_eq!(guard.len(), usable);
+        assert!(guard.iter().all(|&b| b == 0xff));
+    }
+
+    #[test]
+    fn eviction_keeps_page_table_consistent_with_every_policy() {
+        let policies = [
+            ReplacementPolicy::Clock,
+            ReplacementPolicy::Lru,
+            ReplacementPolicy::LruK(2),
+            ReplacementPolicy::TwoQueue,
+        ];
+        for policy in policies {
+            let pool = BufferPool::with_policy(4, PAGE_SIZE, policy);
+            let (bufmgr, page_ids) = memory_pool(pool, 10);
+            for step in 0..100 {
+                let page_id = page_ids[(step * 7) % page_ids.len()];
+                bufmgr.fetch_page_write(page_id).unwrap()[0] = page_id.to_u64() as u8;
+                assert_consistent(&bufmgr);
+            }
+            for &page_id in &page_ids {
+                let page = bufmgr.fetch_page_read(page_id).unwrap();
+                assert_eq!(page[0], page_id.to_u64() as u8);
+            }
+            assert_consistent(&bufmgr);
+        }
+    }
+
+    #[test]
+    fn prefetch_past_the_end_releases_unused_frames() {
+        let (bufmgr, page_ids) = memory_pool(BufferPool::new(8, PAGE_SIZE), 3);
+        let prefetched = bufmgr.prefetch(page_ids[0], 6).unwrap();
+        assert_eq!(prefetched, 3);
+        assert_eq!(free_frame_count(&bufmgr), 5);
+        assert_consistent(&bufmgr);
+    }
+
+    #[test]
+    fn bulk_read_strategy_keeps_hot_pages_cached() {
+        let (bufmgr, page_ids) = memory_pool(BufferPool::new(16, PAGE_SIZE), 40);
+        let (hot, cold) = page_ids.split_at(8);
+        for &page_id in hot {
+            touch(&bufmgr, page_id);
+        }
+        let mut strategy = bufmgr.access_strategy(AccessStrategyKind::BulkRead);
+        assert_eq!(strategy.ring_size(), 2);
+        for &page_id in cold {
+            bufmgr.fetch_page_with(page_id, &mut strategy).unwrap();
+            bufmgr.unpin_page(page_id, false).unwrap();
+            assert_consistent(&bufmgr);
+        }
+        bufmgr.reset_stats();
+        for &page_id in hot {
+            touch(&bufmgr, page_id);
+        }
+        assert_eq!(bufmgr.stats().misses, 0);
+    }
+
+    #[test]
+    fn resize_and_compact_keep_page_table_consistent() {
+        let (bufmgr, page_ids) = memory_pool(BufferPool::new(4, PAGE_SIZE), 8);
+        for &page_id in &page_ids[..4] {
+            bufmgr.fetch_page(page_id).unwrap().write()[0] = 1;
+            bufmgr.unpin_page(page_id, false).unwrap();
+        }
+        bufmgr.resize(6).unwrap();
+        assert_eq!(free_frame_count(&bufmgr), 2);
+        assert_consistent(&bufmgr);
+
+        // ピン留めされたページは残し、他のバッファを外して縮める
+        let pinned = bufmgr.fetch_page(page_ids[3]).unwrap();
+        bufmgr.resize(2).unwrap();
+        assert_consistent(&bufmgr);
+        assert!(std::ptr::eq(
+            pinned,
+            bufmgr.fetch_page(page_ids[3]).unwrap()
+        ));
+        bufmgr.unpin_page(page_ids[3], false).unwrap();
+        // 残すバッファより多くピン留めされていれば縮められない
+        bufmgr.fetch_page(page_ids[0]).unwrap();
+        assert!(matches!(bufmgr.resize(1), Err(MyError::PagePinned(_))));
+        assert_consistent(&bufmgr);
+        bufmgr.unpin_page(page_ids[0], false).unwrap();
+        bufmgr.unpin_page(page_ids[3], false).unwrap();
+        for &page_id in &page_ids[..4] {
+            assert_eq!(bufmgr.fetch_page(page_id).unwrap().read()[0], 1);
+            bufmgr.unpin_page(page_id, false).unwrap();
+            assert_consistent(&bufmgr);
+        }
+
+        bufmgr.compact(&mut |_, _, _| false).unwrap();
+        assert_eq!(free_frame_count(&bufmgr), 2);
+        assert_consistent(&bufmgr);
+    }
+
+    #[test]
+    fn pinned_pages_are_not_evicted_until_unpinned() {
+        let (bufmgr, page_ids) = memory_pool(BufferPool::new(2, PAGE_SIZE), 3);
+        bufmgr.fetch_page(page_ids[0]).unwrap();
+        bufmgr.fetch_page(page_ids[1]).unwrap();
+        // 参照を手放してもピンは外れない
+        assert!(matches!(
+            bufmgr.fetch_page(page_ids[2]),
+            Err(MyError::NoFreeBuffer)
+        ));
+        assert_consistent(&bufmgr);
+
+        bufmgr.unpin_page(page_ids[0], true).unwrap();
+        assert!(matches!(
+            bufmgr.unpin_page(page_ids[0], false),
+            Err(MyError::PageNotPinned(_))
+        ));
+        touch(&bufmgr, page_ids[2]);
+        assert_eq!(bufmgr.stats().dirty_writebacks, 1);
+        assert_consistent(&bufmgr);
+    }
+
+    #[test]
+    fn pin_leaks_report_the_call_sites() {
+        let (bufmgr, page_ids) = memory_pool(BufferPool::new(4, PAGE_SIZE), 3);
+        bufmgr.set_pin_tracking(true);
+        touch(&bufmgr, page_ids[0]);
+        let line = line!() + 1;
+        bufmgr.fetch_page(page_ids[1]).unwrap();
+        let guard = bufmgr.fetch_page_read(page_ids[2]).unwrap();
+
+        let leaks = bufmgr.pin_leaks();
+        assert_eq!(leaks.len(), 2);
+        let pinned = leaks
+            .iter()
+            .find(|leak| leak.page_id == page_ids[1])
+            .unwrap();
+        assert_eq!(pinned.pin_count, 1);
+        assert_eq!(pinned.pinned_at.len(), 1);
+        assert_eq!(pinned.pinned_at[0].file(), file!());
+        assert_eq!(pinned.pinned_at[0].line(), line);
+        // ガードが持っているピン
+        assert!(leaks.iter().any(|leak| leak.page_id == page_ids[2]));
+
+        drop(guard);
+        bufmgr.unpin_page(page_ids[1], false).unwrap();
+        assert!(bufmgr.pin_leaks().is_empty());
+    }
+
+    #[test]
+    fn page_guards_unpin_on_drop() {
+        let (bufmgr, page_ids) = memory_pool(BufferPool::new(1, PAGE_SIZE), 2);
+        {
+            let guard = bufmgr.fetch_page_read(page_ids[0]).unwrap();
+            assert_eq!(guard.page_id(), page_ids[0]);
+            assert!(matches!(
+                bufmgr.fetch_page(page_ids[1]),
+                Err(MyError::NoFreeBuffer)
+            ));
+        }
+        assert!(bufmgr.pin_leaks().is_empty());
+        // 読むだけなら変更済みの印は付かない
+        assert!(!bufmgr.fetch_page_write(page_ids[1]).unwrap().is_empty());
+        assert_eq!(bufmgr.stats().dirty_writebacks, 0);
+
+        bufmgr.fetch_page_write(page_ids[0]).unwrap()[0] = 7;
+        assert!(bufmgr.pin_leaks().is_empty());
+        touch(&bufmgr, page_ids[1]);
+        assert_eq!(bufmgr.stats().dirty_writebacks, 1);
+        assert_eq!(bufmgr.fetch_page_read(page_ids[0]).unwrap()[0], 7);
+
+        let page_id = {
+            let mut guard = bufmgr.create_page_write().unwrap();
+            guard[0] = 9;
+            guard.page_id()
+        };
+        assert!(bufmgr.pin_leaks().is_empty());
+        assert_eq!(bufmgr.fetch_page_read(page_id).unwrap()[0], 9);
+        assert_consistent(&bufmgr);
+    }
+
+    // 指定したページを最初に読み書きするスレッドを、open を呼ぶまで止める
+    #[derive(Default)]
+    struct Gate {
+        state: Mutex<GateState>,
+        changed: Condvar,
+    }
+
+    #[derive(Default)]
+    struct GateState {
+        page_id: Option<PageId>,
+        // 止まっているスレッドの数
+        blocked: usize,
+        opened: bool,
+    }
+
+    impl Gate {
+        fn close(&self, page_id: PageId) {
+            let mut state = self.state.lock().unwrap();
+            state.page_id = Some(page_id);
+            state.opened = false;
+        }
+
+        fn open(&self) {
+            self.state.lock().unwrap().opened = true;
+            self.changed.notify_all();
+        }
+
+        fn pass(&self, page_id: PageId) {
+            let mut state = self.state.lock().unwrap();
+            if state.page_id != Some(page_id) {
+                return;
+            }
+            state.page_id = None;
+            state.blocked += 1;
+            self.changed.notify_all();
+            let mut state = self
+                .changed
+                .wait_while(state, |state| !state.opened)
+                .unwrap();
+            state.blocked -= 1;
+        }
+
+        fn wait_blocked(&self) {
+            let state = self.state.lock().unwrap();
+            drop(
+                self.changed
+                    .wait_while(state, |state| state.blocked == 0)
+                    .unwrap(),
+            );
+        }
+    }
+
+    struct GatedStore {
+        inner: MemoryPageStore,
+        gate: Arc<Gate>,
+    }
+
+    impl PageStore for GatedStore {
+        fn page_size(&self) -> usize {
+            self.inner.page_size()
+        }
+
+        fn read_page_data(&self, page_id: PageId, data: &mut [u8]) -> Result<(), MyError> {
+            self.gate.pass(page_id);
+            self.inner.read_page_data(page_id, data)
+        }
+
+        fn read_pages(
+            &self,
+            first_page_id: PageId,
+            pages: &mut [&mut [u8]],
+        ) -> Result<usize, MyError> {
+            self.gate.pass(first_page_id);
+            self.inner.read_pages(first_page_id, pages)
+        }
+
+        fn write_page_data(&self, page_id: PageId, data: &mut [u8]) -> Result<(), MyError> {
+            self.gate.pass(page_id);
+            self.inner.write_page_data(page_id, data)
+        }
+
+        fn allocate_page(&self) -> Result<PageId, MyError> {
+            self.inner.allocate_page()
+        }
+
+        fn deallocate_page(&self, page_id: PageId) -> Result<(), MyError> {
+            self.inner.deallocate_page(page_id)
+        }
+
+        fn sync(&self) -> Result<(), MyError> {
+            self.inner.sync()
+        }
+
+        fn durability(&self) -> DurabilityMode {
+            self.inner.durability()
+        }
+
+        fn compact(&self, rewriter: &mut ReferenceRewriter) -> Result<Relocations, MyError> {
+            self.inner.compact(rewriter)
+        }
+
+        fn stats(&self) -> DiskStats {
+            self.inner.stats()
+        }
+
+        fn reset_stats(&self) {
+            self.inner.reset_stats()
+        }
+    }
+
+    fn gated_pool(
+        pool_size: usize,
+        page_count: usize,
+    ) -> (BufferPoolManager, Vec<PageId>, Arc<Gate>) {
+        let inner = MemoryPageStore::new(PAGE_SIZE).unwrap();
+        let page_ids = (0..page_count)
+            .map(|_| inner.allocate_page().unwrap())
+            .collect();
+        let gate = Arc::new(Gate::default());
+        let store = GatedStore {
+            inner,
+            gate: Arc::clone(&gate),
+        };
+        let bufmgr = BufferPoolManager::new(Box::new(store), BufferPool::new(pool_size, PAGE_SIZE));
+        bufmgr.set_read_ahead(0);
+        (bufmgr, page_ids, gate)
+    }
+
+    #[test]
+    fn blocked_read_does_not_block_cached_pages() {
+        let (bufmgr, page_ids, gate) = gated_pool(4, 3);
+        bufmgr.fetch_page_write(page_ids[0]).unwrap()[0] = 1;
+        gate.close(page_ids[1]);
+        thread::scope(|s| {
+            let reader = s.spawn(|| bufmgr.fetch_page_read(page_ids[1]).unwrap()[0]);
+            gate.wait_blocked();
+            // 読み込みを待っている間も、キャッシュされたページと他のページは使える
+            assert_eq!(bufmgr.fetch_page_read(page_ids[0]).unwrap()[0], 1);
+            touch(&bufmgr, page_ids[2]);
+            gate.open();
+            assert_eq!(reader.join().unwrap(), 0);
+        });
+        assert!(bufmgr.pin_leaks().is_empty());
+        assert_consistent(&bufmgr);
+    }
+
+    #[test]
+    fn concurrent_misses_read_the_page_once() {
+        let (bufmgr, page_ids, gate) = gated_pool(4, 2);
+        gate.close(page_ids[1]);
+        thread::scope(|s| {
+            let first =
+                s.spawn(|| bufmgr.fetch_page(page_ids[1]).unwrap() as *const Buffer as usize);
+            gate.wait_blocked();
+            let second =
+                s.spawn(|| bufmgr.fetch_page(page_ids[1]).unwrap() as *const Buffer as usize);
+            // 二つ目のスレッドがピン留めして待つまで待つ
+            let io_pins = || {
+                let state = bufmgr.lock();
+                state.pool[state.page_table[&page_ids[1]]].io_pins
+            };
+            while io_pins() != 2 {
+                thread::yield_now();
+            }
+            gate.open();
+            assert_eq!(first.join().unwrap(), second.join().unwrap());
+        });
+        let stats = bufmgr.stats();
+        assert_eq!((stats.misses, stats.hits), (1, 1));
+        assert_eq!(stats.disk.pages_read, 1);
+        bufmgr.unpin_page(page_ids[1], false).unwrap();
+        bufmgr.unpin_page(page_ids[1], false).unwrap();
+        assert_consistent(&bufmgr);
+    }
+
+    #[test]
+    fn dirty_eviction_writes_without_holding_the_page_table() {
+        let (bufmgr, page_ids, gate) = gated_pool(2, 3);
+        bufmgr.fetch_page_write(page_ids[0]).unwrap()[0] = 1;
+        // 追い出されるのが page_ids[0] になるよう、もう一つのバッファはピン留めしておく
+        bufmgr.fetch_page(page_ids[1]).unwrap();
+        gate.close(page_ids[0]);
+        thread::scope(|s| {
+            let loader = s.spawn(|| bufmgr.fetch_page_read(page_ids[2]).unwrap()[0]);
+            gate.wait_blocked();
+            touch(&bufmgr, page_ids[1]);
+            gate.open();
+            assert_eq!(loader.join().unwrap(), 0);
+        });
+        bufmgr.unpin_page(page_ids[1], false).unwrap();
+        assert_eq!(bufmgr.stats().dirty_writebacks, 1);
+        assert_eq!(bufmgr.fetch_page_read(page_ids[0]).unwrap()[0], 1);
+        assert_consistent(&bufmgr);
+    }
+
+    #[test]
+    fn concurrent_flushes_keep_the_newest_contents() {
+        let (bufmgr, page_ids, gate) = gated_pool(4, 1);
+        let page_id = page_ids[0];
+        let buffer = bufmgr.fetch_page(page_id).unwrap();
+        bufmgr.unpin_page(page_id, false).unwrap();
+        bufmgr.fetch_page_write(page_id).unwrap()[0] = 1;
+        gate.close(page_id);
+        thread::scope(|s| {
+            // 1 を書き込む途中で止める
+            let first = s.spawn(|| bufmgr.flush_page(page_id));
+            gate.wait_blocked();
+            bufmgr.fetch_page_write(page_id).unwrap()[0] = 2;
+            let second = s.spawn(|| bufmgr.flush_page(page_id));
+            // 二つ目が 2 を複製するまで待つ
+            while buffer.is_dirty() {
+                thread::yield_now();
+            }
+            gate.open();
+            first.join().unwrap().unwrap();
+            second.join().unwrap().unwrap();
+        });
+        let mut data = vec![0u8; PAGE_SIZE];
+        bufmgr.disk.read_page_data(page_id, &mut data).unwrap();
+        assert_eq!(data[0], 2);
+        assert!(!buffer.is_dirty());
+    }
+
+    #[test]
+    fn background_writes_during_updates_keep_the_last_contents() {
+        let (bufmgr, page_ids) = memory_pool(BufferPool::new(8, PAGE_SIZE), 4);
+        let bufmgr = &bufmgr;
+        let page_ids = &page_ids;
+        let done = &AtomicBool::new(false);
+        thread::scope(|s| {
+            let writers: Vec<_> = page_ids
+                .iter()
+                .map(|&page_id| {
+                    s.spawn(move || {
+                        for i in 1..=200u32 {
+                            bufmgr.fetch_page_write(page_id).unwrap()[..4]
+                                .copy_from_slice(&i.to_le_bytes());
+                        }
+                    })
+                })
+                .collect();
+            for _ in 0..2 {
+                s.spawn(move || {
+                    while !done.load(Ordering::SeqCst) {
+                        bufmgr.write_dirty_buffers(4).unwrap();
+                        for &page_id in page_ids {
+                            bufmgr.flush_page(page_id).unwrap();
+                        }
+                    }
+                });
+            }
+            for writer in writers {
+                writer.join().unwrap();
+            }
+            done.store(true, Ordering::SeqCst);
+        });
+        bufmgr.flush_all().unwrap();
+        let mut data = vec![0u8; PAGE_SIZE];
+        for &page_id in page_ids {
+            bufmgr.disk.read_page_data(page_id, &mut data).unwrap();
+            assert_eq!(data[..4], 200u32.to_le_bytes());
+        }
+        assert_consistent(bufmgr);
+    }
+
+    #[test]
+    fn sequential_fetches_read_ahead() {
+        let (bufmgr, page_ids) = memory_pool(BufferPool::new(16, PAGE_SIZE), 10);
+        bufmgr.set_read_ahead(3);
+        touch(&bufmgr, page_ids[0]);
+        touch(&bufmgr, page_ids[5]);
+        assert_eq!(bufmgr.stats().prefetched_pages, 0);
+        // 連続した二つ目のアクセスで続きの 3 ページを読む
+        touch(&bufmgr, page_ids[6]);
+        assert_eq!(bufmgr.stats().prefetched_pages, 3);
+        bufmgr.reset_stats();
+        for &page_id in &page_ids[7..10] {
+            touch(&bufmgr, page_id);
+        }
+        assert_eq!(bufmgr.stats().misses, 0);
+        assert_consistent(&bufmgr);
+    }
+
+    #[test]
+    fn read_ahead_does_not_write_back_dirty_pages() {
+        let (bufmgr, page_ids) = memory_pool(BufferPool::new(4, PAGE_SIZE), 8);
+        for &page_id in &page_ids[..3] {
+            bufmgr.fetch_page_write(page_id).unwrap()[0] = 1;
+        }
+        touch(&bufmgr, page_ids[3]);
+        // 変更されていないのは page_ids[3] のバッファだけ
+        assert_eq!(bufmgr.prefetch(page_ids[4], 4).unwrap(), 1);
+        assert_eq!(bufmgr.stats().dirty_writebacks, 0);
+        assert_eq!(bufmgr.prefetch(page_ids[5], 3).unwrap(), 1);
+        assert_eq!(bufmgr.stats().dirty_writebacks, 0);
+        assert_consistent(&bufmgr);
+    }
+
+    #[test]
+    fn delete_page_discards_the_buffer_without_writing() {
+        let (bufmgr, _) = memory_pool(BufferPool::new(4, PAGE_SIZE), 0);
+        let page_id = {
+            let mut guard = bufmgr.create_page_write().unwrap();
+            guard[0] = 1;
+            assert!(matches!(
+                bufmgr.delete_page(guard.page_id()),
+                Err(MyError::PagePinned(_))
+            ));
+            guard.page_id()
+        };
+        bufmgr.reset_stats();
+        bufmgr.delete_page(page_id).unwrap();
+        assert_eq!(bufmgr.stats().disk.pages_written, 0);
+        assert_eq!(free_frame_count(&bufmgr), 4);
+        assert_consistent(&bufmgr);
+        assert!(matches!(
+            bufmgr.delete_page(page_id),
+            Err(MyError::PageAlreadyFree(_))
+        ));
+
+        // 解放したページは再利用され、中身はゼロに戻っている
+        let guard = bufmgr.create_page_write().unwrap();
+        assert_eq!(guard.page_id(), page_id);
+        assert_eq!(guard[0], 0);
+        drop(guard);
+        bufmgr.flush_all().unwrap();
+        assert_eq!(bufmgr.fetch_page_read(page_id).unwrap()[0], 0);
+    }
+
+    #[test]
+    fn stats_count_pool_and_disk_activity() {
+        let injector = FaultInjector::new();
+        let disk = DiskManager::with_options(injector.file(), without_double_write()).unwrap();
+        let page_ids: Vec<PageId> = (0..3).map(|_| disk.allocate_page().unwrap()).collect();
+        let bufmgr = BufferPoolManager::new(Box::new(disk), BufferPool::new(2, PAGE_SIZE));
+        bufmgr.set_read_ahead(0);
+        bufmgr.reset_stats();
+
+        touch(&bufmgr, page_ids[0]);
+        touch(&bufmgr, page_ids[0]);
+        bufmgr.fetch_page_write(page_ids[1]).unwrap()[0] = 1;
+        // 参照の少ない page_ids[1] を書き戻して追い出す
+        touch(&bufmgr, page_ids[2]);
+        let stats = bufmgr.stats();
+        assert_eq!((stats.hits, stats.misses), (1, 3));
+        assert_eq!((stats.evictions, stats.dirty_writebacks), (1, 1));
+        assert_eq!((stats.disk.pages_read, stats.disk.pages_written), (3, 1));
+        assert_eq!(stats.disk.bytes_read, 3 * PAGE_SIZE as u64);
+        assert_eq!(stats.disk.bytes_written, PAGE_SIZE as u64);
+        assert_eq!(stats.disk.syncs, 0);
+        bufmgr.flush_all().unwrap();
+        assert_eq!(bufmgr.stats().disk.syncs, 1);
+
+        bufmgr.reset_stats();
+        let stats = bufmgr.stats();
+        assert_eq!((stats.hits, stats.misses, stats.evictions), (0, 0, 0));
+        assert_eq!(stats.dirty_writebacks, 0);
+        assert_eq!((stats.disk.pages_read, stats.disk.syncs), (0, 0));
+    }
+
+    #[test]
+    fn create_page_returns_a_zeroed_page_without_reading() {
+        let (bufmgr, page_ids) = memory_pool(BufferPool::new(1, PAGE_SIZE), 1);
+        // 前に置かれていたページの内容が残っていないこと
+        bufmgr.fetch_page_write(page_ids[0]).unwrap().fill(0xaa);
+        bufmgr.flush_all().unwrap();
+        bufmgr.reset_stats();
+        let page_id = {
+            let guard = bufmgr.create_page_write().unwrap();
+            assert!(guard.iter().all(|&b| b == 0));
+            guard.page_id()
+        };
+        assert_ne!(page_id, page_ids[0]);
+        let stats = bufmgr.stats();
+        assert_eq!(stats.disk.pages_read, 0);
+        assert_eq!((stats.hits, stats.misses), (0, 0));
+        // 書き込まれていないページも、追い出すときに書き込まれる
+        touch(&bufmgr, page_ids[0]);
+        assert_eq!(bufmgr.stats().dirty_writebacks, 1);
+        assert!(bufmgr
+            .fetch_page_read(page_id)
+            .unwrap()
+            .iter()
+            .all(|&b| b == 0));
+        assert_consistent(&bufmgr);
+    }
+
+    // 二つのページを変更して flush し、クラッシュした後に残っている内容を返す
+    fn contents_after_crash(flush: impl FnOnce(BufferPoolManager, &[PageId])) -> Vec<u8> {
+        let injector = FaultInjector::new();
+        let disk =
+            DiskManager::with_options(injector.file(), DiskManagerOptions::default()).unwrap();
+        let page_ids: Vec<PageId> = (0..2).map(|_| disk.allocate_page().unwrap()).collect();
+        disk.sync().unwrap();
+        let bufmgr = BufferPoolManager::new(Box::new(disk), BufferPool::new(4, PAGE_SIZE));
+        for (i, &page_id) in page_ids.iter().enumerate() {
+            bufmgr.fetch_page_write(page_id).unwrap()[0] = i as u8 + 1;
+        }
+        flush(bufmgr, &page_ids);
+        injector.crash();
+        let disk =
+            DiskManager::with_options(injector.file(), DiskManagerOptions::default()).unwrap();
+        page_ids
+            .iter()
+            .map(|&page_id| {
+                let mut data = vec![0u8; PAGE_SIZE];
+                disk.read_page_data(page_id, &mut data).unwrap();
+                data[0]
+            })
+            .collect()
+    }
+
+    #[test]
+    fn flushed_pages_survive_a_crash() {
+        let flushed = contents_after_crash(|bufmgr, page_ids| {
+            bufmgr.flush_page(page_ids[0]).unwrap();
+            // 破棄する前にクラッシュしたものとして、書き込まずに捨てる
+            std::mem::forget(bufmgr);
+        });
+        assert_eq!(flushed, [1, 0]);
+        let flushed = contents_after_crash(|bufmgr, _| {
+            bufmgr.flush_all().unwrap();
+            std::mem::forget(bufmgr);
+        });
+        assert_eq!(flushed, [1, 2]);
+        assert_eq!(contents_after_crash(|bufmgr, _| drop(bufmgr)), [1, 2]);
+    }
+
+    #[test]
+    fn resize_under_load_keeps_page_contents() {
+        let (bufmgr, page_ids) = memory_pool(BufferPool::new(8, PAGE_SIZE), 7);
+        let bufmgr = &bufmgr;
+        let done = &AtomicBool::new(false);
+        // ずっとピン留めしておくページがあっても縮められる
+        let held = bufmgr.fetch_page_write(page_ids[6]).unwrap();
+        thread::scope(|s| {
+            // 各スレッドが自分の二つのページに書き、直前に書いた値が残っているか確かめる
+            let writers: Vec<_> = page_ids[..6]
+                .chunks(2)
+                .map(|pages| {
+                    s.spawn(move || {
+                        for i in 1..=200u32 {
+                            for &page_id in pages {
+                                let mut page = bufmgr.fetch_page_write(page_id).unwrap();
+                                assert_eq!(page[..4], (i - 1).to_le_bytes());
+                                page[..4].copy_from_slice(&i.to_le_bytes());
+                            }
+                        }
+                    })
+                })
+                .collect();
+            s.spawn(move || {
+                // ピン留めされうるバッファの数 (書き込むスレッドと held) より少なくは縮めない
+                let sizes = [4, 12, 4, 16, 5, 8];
+                for (n, &pool_size) in sizes.iter().cycle().enumerate() {
+                    // 少なくとも一周は大きさを変える
+                    if n >= sizes.len() && done.load(Ordering::SeqCst) {
+                        break;
+                    }
+                    bufmgr.resize(pool_size).unwrap();
+                    assert_eq!(bufmgr.lock().pool.size(), pool_size);
+                }
+            });
+            for writer in writers {
+                writer.join().unwrap();
+            }
+            done.store(true, Ordering::SeqCst);
+        });
+        drop(held);
+        assert_consistent(bufmgr);
+        bufmgr.flush_all().unwrap();
+        let mut data = vec![0u8; PAGE_SIZE];
+        for &page_id in &page_ids[..6] {
+            bufmgr.disk.read_page_data(page_id, &mut data).unwrap();
+            assert_eq!(data[..4], 200u32.to_le_bytes());
+        }
+    }
+
+    // 二重書き込みしない設定 (書き戻しがそのまま一回の書き込みになる)
+    fn without_double_write() -> DiskManagerOptions {
+        DiskManagerOptions {
+            double_write_slots: 0,
+            ..Default::default()
+        }
+    }
+
+    // 障害を注入できるファイルの上にバッファプールを作る (割り当てたページは sync 済み)
+    fn faulty_pool(
+        pool_size: usize,
+        page_count: usize,
+    ) -> (FaultInjector, BufferPoolManager, Vec<PageId>) {
+        let injector = FaultInjector::new();
+        let disk = DiskManager::with_options(injector.file(), without_double_write()).unwrap();
+        let page_ids: Vec<PageId> = (0..page_count)
+            .map(|_| disk.allocate_page().unwrap())
+            .collect();
+        disk.sync().unwrap();
+        let bufmgr = BufferPoolManager::new(Box::new(disk), BufferPool::new(pool_size, PAGE_SIZE));
+        bufmgr.set_read_ahead(0);
+        (injector, bufmgr, page_ids)
+    }
+
+    fn read_from_disk(bufmgr: &BufferPoolManager, page_id: PageId) -> Result<u8, MyError> {
+        let mut data = vec![0u8; PAGE_SIZE];
+        bufmgr.disk.read_page_data(page_id, &mut data)?;
+        Ok(data[0])
+    }
+
+    #[test]
+    fn failed_write_back_keeps_the_dirty_page() {
+        for short in [false, true] {
+            let (injector, bufmgr, page_ids) = faulty_pool(1, 2);
+            bufmgr.fetch_page_write(page_ids[0]).unwrap()[0] = 7;
+            if short {
+                injector.short_nth_write(1, 100);
+            } else {
+                injector.fail_nth_write(1);
+            }
+            // 追い出すページを書き戻せなければ、読み込みも作成も失敗する
+            assert!(matches!(
+                bufmgr.fetch_page(page_ids[1]),
+                Err(MyError::Io(_))
+            ));
+            assert_consistent(&bufmgr);
+            // create_page は先にスーパーブロックを書くので、二回目の書き込みが追い出し
+            // 失敗すると割り当てたページを解放する (解放の印とスーパーブロックの二回)
+            injector.fail_nth_write(2);
+            let writes = injector.write_count();
+            assert!(matches!(bufmgr.create_page(), Err(MyError::Io(_))));
+            assert_eq!(injector.write_count(), writes + 4);
+            assert_consistent(&bufmgr);
+
+            // 変更は失われず、書き込めるようになれば追い出せる
+            let guard = bufmgr.fetch_page_read(page_ids[0]).unwrap();
+            assert_eq!(guard[0], 7);
+            drop(guard);
+            assert!(bufmgr.buffer(BufferId(0)).is_dirty());
+            touch(&bufmgr, page_ids[1]);
+            assert_consistent(&bufmgr);
+            assert_eq!(read_from_disk(&bufmgr, page_ids[0]).unwrap(), 7);
+        }
+    }
+
+    #[test]
+    fn torn_write_back_is_detected_on_the_next_read() {
+        let (injector, bufmgr, page_ids) = faulty_pool(1, 2);
+        bufmgr.fetch_page_write(page_ids[0]).unwrap()[0] = 7;
+        // 書きかけで成功したことになるので、追い出しは成功する
+        injector.tear_nth_write(1, 100);
+        touch(&bufmgr, page_ids[1]);
+        assert_consistent(&bufmgr);
+        assert!(matches!(
+            bufmgr.fetch_page(page_ids[0]),
+            Err(MyError::PageCorrupted { page_id, .. }) if page_id == page_ids[0]
+        ));
+        assert_consistent(&bufmgr);
+        assert_eq!(free_frame_count(&bufmgr), 1);
+    }
+
+    #[test]
+    fn crash_discards_write_backs_after_the_last_checkpoint() {
+        let (injector, bufmgr, page_ids) = faulty_pool(1, 2);
+        bufmgr.fetch_page_write(page_ids[0]).unwrap()[0] = 1;
+        bufmgr.flush_all().unwrap();
+        bufmgr.fetch_page_write(page_ids[0]).unwrap()[0] = 2;
+        // 追い出しで書き戻しても sync されるまではクラッシュで失われる
+        touch(&bufmgr, page_ids[1]);
+        assert_eq!(read_from_disk(&bufmgr, page_ids[0]).unwrap(), 2);
+        std::mem::forget(bufmgr);
+        injector.crash();
+
+        let disk =
+            DiskManager::with_options(injector.file(), DiskManagerOptions::default()).unwrap();
+        let bufmgr = BufferPoolManager::new(Box::new(disk), BufferPool::new(1, PAGE_SIZE));
+        assert_eq!(bufmgr.fetch_page_read(page_ids[0]).unwrap()[0], 1);
+    }
+
+    #[test]
+    fn created_page_is_never_cached_twice() {
+        let (_injector, bufmgr, _) = faulty_pool(8, 0);
+        bufmgr.set_read_ahead(2);
+        let page_ids: Vec<PageId> = (0..2)
+            .map(|_| bufmgr.create_page_write().unwrap().page_id())
+            .collect();
+        // キャッシュを空にしてから順に読み直す
+        bufmgr.resize(1).unwrap();
+        bufmgr.resize(8).unwrap();
+        touch(&bufmgr, page_ids[0]);
+        touch(&bufmgr, page_ids[1]);
+        // 拡張しただけのページは先読みしない
+        assert_eq!(bufmgr.stats().prefetched_pages, 0);
+
+        // 先読みやフェッチで残った古い内容があっても、作ったページは一つのバッファにだけ置く
+        let next = PageId(page_ids[1].to_u64() + 1);
+        assert!(bufmgr.fetch_page(next).is_ok());
+        assert!(matches!(
+            bufmgr.create_page(),
+            Err(MyError::PagePinned(page_id)) if page_id == next
+        ));
+        bufmgr.unpin_page(next, false).unwrap();
+        let mut guard = bufmgr.create_page_write().unwrap();
+        assert_eq!(guard.page_id(), next);
+        guard[0] = 9;
+        drop(guard);
+        assert_consistent(&bufmgr);
+        bufmgr.flush_all().unwrap();
+        assert_eq!(read_from_disk(&bufmgr, next).unwrap(), 9);
+
+        // 解放済みのページも先読みしない
+        bufmgr.delete_page(page_ids[1]).unwrap();
+        assert_eq!(bufmgr.prefetch(page_ids[1], 2).unwrap(), 0);
+        assert_consistent(&bufmgr);
+    }
+
+    #[test]
+    fn unpin_without_a_pin_is_rejected_during_a_flush() {
+        let (bufmgr, page_ids, gate) = gated_pool(4, 1);
+        bufmgr.fetch_page_write(page_ids[0]).unwrap()[0] = 1;
+        gate.close(page_ids[0]);
+        thread::scope(|s| {
+            let flusher = s.spawn(|| bufmgr.flush_page(page_ids[0]));
+            gate.wait_blocked();
+            // 書き込みのためのピンは呼び出し側のピンとして外せない
+            assert!(matches!(
+                bufmgr.unpin_page(page_ids[0], false),
+                Err(MyError::PageNotPinned(page_id)) if page_id == page_ids[0]
+            ));
+            assert!(bufmgr.pin_leaks().is_empty());
+            gate.open();
+            flusher.join().unwrap().unwrap();
+        });
+        assert_consistent(&bufmgr);
+        touch(&bufmgr, page_ids[0]);
+    }
+
+    #[test]
+    fn flush_while_holding_the_latch_does_not_deadlock() {
+        let (bufmgr, page_ids) = memory_pool(BufferPool::new(2, PAGE_SIZE), 1);
+        let mut guard = bufmgr.fetch_page_write(page_ids[0]).unwrap();
+        guard[0] = 1;
+        // ラッチを持っているページは書き込まれず、変更済みのまま残る
+        bufmgr.flush_page(page_ids[0]).unwrap();
+        bufmgr.flush_all().unwrap();
+        assert_eq!(bufmgr.stats().disk.pages_written, 0);
+        drop(guard);
+        bufmgr.flush_page(page_ids[0]).unwrap();
+        let mut data = vec![0u8; PAGE_SIZE];
+        bufmgr.disk.read_page_data(page_ids[0], &mut data).unwrap();
+        assert_eq!(data[0], 1);
+    }
+}
@@ -0,0 +1,1786 @@
+use std::fs::{File, OpenOptions, TryLockError};
+use std::io::{Error, ErrorKind};
+use std::path::Path;
+use std::sync::Mutex;
+
+use crate::checksum::Crc32c;
+use crate::segment::{segment_path, SegmentedFile, DEFAULT_SEGMENT_SIZE};
+use crate::stats::{DiskCounters, DiskStats};
+use crate::store::{plan_compaction, PageStore, ReferenceRewriter, Relocations};
+use crate::MyError;
+
+// データベース作成時に指定できるページサイズ
+pub const DEFAULT_PAGE_SIZE: usize = 4096;
+pub const MIN_PAGE_SIZE: usize = 4096;
+pub const MAX_PAGE_SIZE: usize = 32768;
+// 各ページの末尾はチェックサム (CRC32C) 用に予約されている
+pub const PAGE_TRAILER_SIZE: usize = 4;
+
+// ページのうち、末尾のチェックサムを除いて呼び出し側が使える大きさ
+pub fn usable_page_size(page_size: usize) -> usize {
+    page_size - PAGE_TRAILER_SIZE
+}
+
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Copy, Clone, Default)]
+pub struct PageId(pub u64);
+impl PageId {
+    pub const INVALID_PAGE_ID: PageId = PageId(u64::MAX);
+
+    pub fn valid(self) -> Option<PageId> {
+        if self == Self::INVALID_PAGE_ID {
+            None
+        } else {
+            Some(self)
+        }
+    }
+
+    pub fn to_u64(self) -> u64 {
+        self.0
+    }
+}
+
+// ページ 0 はスーパーブロックとして予約する
+pub const SUPERBLOCK_PAGE_ID: PageId = PageId(0);
+
+// スーパーブロック先頭のマジックナンバー
+pub const MAGIC: [u8; 8] = *b"MINIRDBM";
+// ディスク上のフォーマットのバージョン
+pub const FORMAT_VERSION: u32 = 3;
+// スーパーブロックに保持するカタログのルートページの数
+pub const CATALOG_ROOT_COUNT: usize = 4;
+
+// スーパーブロックのレイアウト
+// [0..8): マジックナンバー
+// [8..12): フォーマットのバージョン
+// [12..16): ページサイズ
+// [16..24): セグメントファイルの大きさ
+// [24..32): フリーリストの先頭ページID
+// [32..40): 次に採番するページID
+// [40..72): カタログのルートページID
+// [72..76): 二重書き込み領域のスロット数 (0 なら二重書き込みしない)
+const SB_MAGIC: usize = 0;
+const SB_FORMAT_VERSION: usize = 8;
+const SB_PAGE_SIZE: usize = 12;
+const SB_SEGMENT_SIZE: usize = 16;
+const SB_FREE_LIST_HEAD: usize = 24;
+const SB_NEXT_PAGE_ID: usize = 32;
+const SB_CATALOG_ROOTS: usize = 40;
+const SB_DOUBLE_WRITE_SLOTS: usize = 72;
+// ページサイズが分からなくても読めるスーパーブロック先頭部分の大きさ
+const SB_HEADER_SIZE: usize = 24;
+
+// ヒープファイルを一度に拡張するページ数の既定値
+pub const DEFAULT_GROWTH_PAGES: u64 = 16;
+
+// 二重書き込み領域はスーパーブロックの直後に置く
+// ヘッダページに続いてスロット数だけのページが並ぶ
+const DOUBLE_WRITE_HEADER_PAGE_ID: PageId = PageId(1);
+const DOUBLE_WRITE_MAGIC: [u8; 8] = *b"DBLWRITE";
+// 二重書き込み領域のスロット数の既定値
+pub const DEFAULT_DOUBLE_WRITE_SLOTS: u32 = 32;
+
+// 二重書き込みヘッダのレイアウト
+// [0..8): マジックナンバー
+// [8..12): スロットに書いたページの数
+// [16..): 各スロットに書いたページのID
+const DW_MAGIC: usize = 0;
+const DW_COUNT: usize = 8;
+const DW_PAGE_IDS: usize = 16;
+
+// 解放済みページのレイアウト
+// [0..8): フリーリストの次のページID
+// [8..16): 解放済みの印 (二重解放や壊れたフリーリストを検出する)
+const FREE_PAGE_NEXT: usize = 0;
+const FREE_PAGE_MARK: usize = 8;
+const FREE_PAGE_MAGIC: [u8; 8] = *b"FREEPAGE";
+
+fn is_free_page(data: &[u8]) -> bool {
+    data[FREE_PAGE_MARK..FREE_PAGE_MARK + FREE_PAGE_MAGIC.len()] == FREE_PAGE_MAGIC
+}
+
+fn encode_free_page(data: &mut [u8], next_page_id: PageId) {
+    data.fill(0);
+    write_page_id(data, FREE_PAGE_NEXT, next_page_id);
+    data[FREE_PAGE_MARK..FREE_PAGE_MARK + FREE_PAGE_MAGIC.len()].copy_from_slice(&FREE_PAGE_MAGIC);
+}
+
+fn corrupted_free_list(page_id: PageId) -> MyError {
+    Error::new(
+        ErrorKind::InvalidData,
+        format!("free list is corrupted at page {}", page_id.to_u64()),
+    )
+    .into()
+}
+
+fn read_page_id(data: &[u8], offset: usize) -> PageId {
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&data[offset..offset + 8]);
+    PageId(u64::from_le_bytes(bytes))
+}
+
+fn write_page_id(data: &mut [u8], offset: usize, page_id: PageId) {
+    data[offset..offset + 8].copy_from_slice(&page_id.to_u64().to_le_bytes());
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(data: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+}
+
+// 別のページ位置に書かれたデータも検出できるよう、ページIDも含めて計算する
+fn page_checksum(page_id: PageId, body: &[u8]) -> u32 {
+    let mut crc = Crc32c::new();
+    crc.update(&page_id.to_u64().to_le_bytes());
+    crc.update(body);
+    crc.finish()
+}
+
+fn stamp_page_checksum(page_id: PageId, data: &mut [u8]) {
+    let (body, trailer) = data.split_at_mut(data.len() - PAGE_TRAILER_SIZE);
+    trailer.copy_from_slice(&page_checksum(page_id, body).to_le_bytes());
+}
+
+fn verify_page_checksum(page_id: PageId, data: &[u8]) -> Result<(), MyError> {
+    // 一度も書き込まれていない (ゼロ埋めされたままの) ページは検証しない
+    if data.iter().all(|&b| b == 0) {
+        return Ok(());
+    }
+    let (body, trailer) = data.split_at(data.len() - PAGE_TRAILER_SIZE);
+    let expected = read_u32(trailer, 0);
+    let actual = page_checksum(page_id, body);
+    if expected != actual {
+        return Err(MyError::PageCorrupted {
+            page_id,
+            expected,
+            actual,
+        });
+    }
+    Ok(())
+}
+
+fn check_page_len(data: &[u8], page_size: usize) -> std::io::Result<()> {
+    if data.len() != page_size {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!(
+                "page buffer must be {} bytes, got {}",
+                page_size,
+                data.len()
+            ),
+        ));
+    }
+    Ok(())
+}
+
+pub(crate) fn check_page_size(page_size: usize) -> Result<(), MyError> {
+    if !page_size.is_power_of_two() || !(MIN_PAGE_SIZE..=MAX_PAGE_SIZE).contains(&page_size) {
+        return Err(MyError::UnsupportedPageSize(page_size as u32));
+    }
+    Ok(())
+}
+
+// ページがセグメントの境界をまたがないよう、最大のページサイズの倍数に限る
+fn check_segment_size(segment_size: u64) -> Result<(), MyError> {
+    if segment_size == 0 || !segment_size.is_multiple_of(MAX_PAGE_SIZE as u64) {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("unsupported segment size {}", segment_size),
+        )
+        .into());
+    }
+    Ok(())
+}
+
+fn check_catalog_root_index(index: usize) -> Result<(), MyError> {
+    if index >= CATALOG_ROOT_COUNT {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("catalog root index {} is out of range", index),
+        )
+        .into());
+    }
+    Ok(())
+}
+
+// ヘッダページに収まるスロット数まで
+fn max_double_write_slots(page_size: usize) -> u32 {
+    ((page_size - PAGE_TRAILER_SIZE - DW_PAGE_IDS) / 8) as u32
+}
+
+// どのタイミングでファイルを永続化 (fsync) するか
+// 二重書き込み領域があるファイルでは、どのモードでもページを書き込むたびに永続化される
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DurabilityMode {
+    // 明示的に sync() を呼んだときだけ永続化する
+    Off,
+    // flush_all などのチェックポイントで永続化する
+    #[default]
+    Normal,
+    // ページを書き込むたびに永続化する
+    Full,
+}
+
+#[derive(Debug, Clone)]
+pub struct DiskManagerOptions {
+    // ファイル末尾を越えてページを採番するときに拡張するページ数
+    pub growth_pages: u64,
+    // 新規作成時のページサイズ (既存のファイルではスーパーブロックの値を使う)
+    pub page_size: usize,
+    pub durability: DurabilityMode,
+    // 共有ロックで開き、書き込みを禁止する (調査用のツール向け)
+    pub read_only: bool,
+    // open_with_options で新規作成するときのセグメントファイルの大きさ
+    pub segment_size: u64,
+    // 新規作成時に確保する二重書き込み領域のスロット数 (0 なら二重書き込みしない)
+    // 領域があれば durability によらず、ページを書き込むたびに複製と本来の位置で sync を 2 回行う
+    pub double_write_slots: u32,
+}
+
+impl Default for DiskManagerOptions {
+    fn default() -> Self {
+        Self {
+            growth_pages: DEFAULT_GROWTH_PAGES,
+            page_size: DEFAULT_PAGE_SIZE,
+            durability: DurabilityMode::default(),
+            read_only: false,
+            segment_size: DEFAULT_SEGMENT_SIZE,
+            double_write_slots: DEFAULT_DOUBLE_WRITE_SLOTS,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+// ページサイズが分からなくても読めるスーパーブロックの先頭部分
+struct SuperblockHeader {
+    page_size: usize,
+    segment_size: u64,
+}
+
+#[derive(Debug, Clone)]
+struct Superblock {
+    page_size: usize,
+    segment_size: u64,
+    // 採番するページIDを決めるカウンタ
+    next_page_id: u64,
+    // 解放済みページを繋いだリストの先頭
+    free_list_head: PageId,
+    catalog_roots: [PageId; CATALOG_ROOT_COUNT],
+    double_write_slots: u32,
+}
+
+impl Superblock {
+    fn new(page_size: usize, segment_size: u64, double_write_slots: u32) -> Self {
+        // 二重書き込み領域を確保する場合は、その後ろから採番する
+        let mut next_page_id = SUPERBLOCK_PAGE_ID.to_u64() + 1;
+        if double_write_slots > 0 {
+            next_page_id = DOUBLE_WRITE_HEADER_PAGE_ID.to_u64() + 1 + double_write_slots as u64;
+        }
+        Self {
+            page_size,
+            segment_size,
+            next_page_id,
+            free_list_head: PageId::INVALID_PAGE_ID,
+            catalog_roots: [PageId::INVALID_PAGE_ID; CATALOG_ROOT_COUNT],
+            double_write_slots,
+        }
+    }
+
+    // データを置くページとして採番・解放できる最初のページ
+    fn first_data_page_id(&self) -> PageId {
+        if self.double_write_slots > 0 {
+            PageId(DOUBLE_WRITE_HEADER_PAGE_ID.to_u64() + 1 + self.double_write_slots as u64)
+        } else {
+            PageId(SUPERBLOCK_PAGE_ID.to_u64() + 1)
+        }
+    }
+
+    // 先頭部分だけを見てマジックナンバーとバージョンを確かめる
+    fn decode_header(header: &[u8]) -> Result<SuperblockHeader, MyError> {
+        if header[SB_MAGIC..SB_MAGIC + MAGIC.len()] != MAGIC {
+            return Err(MyError::NotADatabase);
+        }
+        let format_version = read_u32(header, SB_FORMAT_VERSION);
+        if format_version != FORMAT_VERSION {
+            return Err(MyError::UnsupportedFormatVersion {
+                found: format_version,
+                supported: FORMAT_VERSION,
+            });
+        }
+        let page_size = read_u32(header, SB_PAGE_SIZE) as usize;
+        check_page_size(page_size)?;
+        let segment_size = read_u64(header, SB_SEGMENT_SIZE);
+        check_segment_size(segment_size)?;
+        Ok(SuperblockHeader {
+            page_size,
+            segment_size,
+        })
+    }
+
+    fn decode(data: &[u8]) -> Result<Self, MyError> {
+        let header = Self::decode_header(data)?;
+        // マジックナンバーとバージョンを確認してからチェックサムを検証する
+        verify_page_checksum(SUPERBLOCK_PAGE_ID, data)?;
+        let mut catalog_roots = [PageId::INVALID_PAGE_ID; CATALOG_ROOT_COUNT];
+        for (i, root) in catalog_roots.iter_mut().enumerate() {
+            *root = read_page_id(data, SB_CATALOG_ROOTS + i * 8);
+        }
+        let double_write_slots = read_u32(data, SB_DOUBLE_WRITE_SLOTS);
+        if double_write_slots > max_double_write_slots(header.page_size) {
+            return Err(MyError::NotADatabase);
+        }
+        Ok(Self {
+            page_size: header.page_size,
+            segment_size: header.segment_size,
+            next_page_id: read_page_id(data, SB_NEXT_PAGE_ID).to_u64(),
+            free_list_head: read_page_id(data, SB_FREE_LIST_HEAD),
+            catalog_roots,
+            double_write_slots,
+        })
+    }
+
+    fn encode(&self, data: &mut [u8]) {
+        data[SB_MAGIC..SB_MAGIC + MAGIC.len()].copy_from_slice(&MAGIC);
+        data[SB_FORMAT_VERSION..SB_FORMAT_VERSION + 4]
+            .copy_from_slice(&FORMAT_VERSION.to_le_bytes());
+        data[SB_PAGE_SIZE..SB_PAGE_SIZE + 4]
+            .copy_from_slice(&(self.page_size as u32).to_le_bytes());
+        data[SB_SEGMENT_SIZE..SB_SEGMENT_SIZE + 8]
+            .copy_from_slice(&self.segment_size.to_le_bytes());
+        write_page_id(data, SB_FREE_LIST_HEAD, self.free_list_head);
+        write_page_id(data, SB_NEXT_PAGE_ID, PageId(self.next_page_id));
+        for (i, &root) in self.catalog_roots.iter().enumerate() {
+            write_page_id(data, SB_CATALOG_ROOTS + i * 8, root);
+        }
+        data[SB_DOUBLE_WRITE_SLOTS..SB_DOUBLE_WRITE_SLOTS + 4]
+            .copy_from_slice(&self.double_write_slots.to_le_bytes());
+    }
+}
+
+// 採番やフリーリストの更新に伴って変化する状態
+struct AllocState {
+    superblock: Superblock,
+    // ヒープファイルに物理的に確保済みのページ数
+    file_pages: u64,
+}
+
+pub struct DiskManager {
+    // ヒープファイルのファイルディスクリプタ
+    heap_file: Box<dyn DiskFile>,
+    page_size: usize,
+    // ページの読み書きは位置指定 I/O なので排他せず、採番まわりだけをロックで守る
+    state: Mutex<AllocState>,
+    double_write_slots: u32,
+    // 二重書き込み領域は一度に一組の書き込みだけが使う
+    double_write: Mutex<()>,
+    counters: DiskCounters,
+    options: DiskManagerOptions,
+}
+
+impl DiskManager {
+    pub fn new(heap_file: File) -> Result<Self, MyError> {
+        Self::with_options(heap_file, DiskManagerOptions::default())
+    }
+
+    pub fn with_options(
+        heap_file: impl DiskFile + 'static,
+        options: DiskManagerOptions,
+    ) -> Result<Self, MyError> {
+        let heap_file: Box<dyn DiskFile> = Box::new(heap_file);
+        if options.growth_pages == 0 {
+            return Err(
+                Error::new(ErrorKind::InvalidInput, "growth_pages must be at least 1").into(),
+            );
+        }
+        check_page_size(options.page_size)?;
+        check_segment_size(options.segment_size)?;
+        if options.double_write_slots > max_double_write_slots(options.page_size) {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("too many double write slots {}", options.double_write_slots),
+            )
+            .into());
+        }
+        let heap_file_size = heap_file.size()?;
+        if heap_file_size == 0 {
+            // 新規ファイルならスーパーブロックを作る
+            let superblock = Superblock::new(
+                options.page_size,
+                options.segment_size,
+                options.double_write_slots,
+            );
+            heap_file.set_len(superblock.next_page_id * options.page_size as u64)?;
+            let disk = Self {
+                heap_file,
+                page_size: options.page_size,
+                double_write_slots: superblock.double_write_slots,
+                double_write: Mutex::new(()),
+                counters: DiskCounters::default(),
+                state: Mutex::new(AllocState {
+                    file_pages: superblock.next_page_id,
+                    superblock,
+                }),
+                options,
+            };
+            disk.write_superblock(&disk.state.lock().unwrap().superblock)?;
+            return Ok(disk);
+        }
+        // ページサイズはスーパーブロックに記録されているので、先に先頭部分だけを読む
+        if heap_file_size < SB_HEADER_SIZE as u64 {
+            return Err(MyError::NotADatabase);
+        }
+        let mut header = [0u8; SB_HEADER_SIZE];
+        heap_file.read_exact_at(&mut header, 0)?;
+        let page_size = Superblock::decode_header(&header)?.page_size;
+        if heap_file_size < page_size as u64 {
+            return Err(MyError::NotADatabase);
+        }
+        let mut data = vec![0u8; page_size];
+        heap_file.read_exact_at(&mut data, 0)?;
+        let superblock = match Superblock::decode(&data) {
+            // スーパーブロック自体の書き込みが途中で途切れていたら二重書き込み領域から復元を試みる
+            Err(MyError::PageCorrupted { .. })
+                if !options.read_only
+                    && recover_double_write(heap_file.as_ref(), page_size)? > 0 =>
+            {
+                heap_file.read_exact_at(&mut data, 0)?;
+                Superblock::decode(&data)?
+            }
+            result => {
+                let superblock = result?;
+                if superblock.double_write_slots > 0 && !options.read_only {
+                    recover_double_write(heap_file.as_ref(), page_size)?;
+                }
+                superblock
+            }
+        };
+        let heap_file_size = heap_file.size()?;
+        Ok(Self {
+            heap_file,
+            page_size,
+            double_write_slots: superblock.double_write_slots,
+            double_write: Mutex::new(()),
+            counters: DiskCounters::default(),
+            state: Mutex::new(AllocState {
+                superblock,
+                file_pages: heap_file_size / page_size as u64,
+            }),
+            options,
+        })
+    }
+
+    pub fn page_size(&self) -> usize {
+        self.page_size
+    }
+
+    // read_page_data / write_page_data にはページ全体を渡すが、末尾は書き込むときに上書きされる
+    pub fn usable_page_size(&self) -> usize {
+        usable_page_size(self.page_size)
+    }
+
+    pub fn read_page_data(&self, page_id: PageId, data: &mut [u8]) -> Result<(), MyError> {
+        check_page_len(data, self.page_size)?;
+        let offset = self.page_size as u64 * page_id.to_u64();
+        self.heap_file.read_exact_at(data, offset)?;
+        self.counters.record_read(data.len());
+        verify_page_checksum(page_id, data)
+    }
+
+    // first_page_id から連続するページを一度の読み出しでまとめて読む
+    // 採番済みの範囲を越える分 (先に拡張しただけのページ) は読まず、解放済みのページに当たったらその手前までにする
+    // 読み出したページ数を返す
+    pub fn read_pages(
+        &self,
+        first_page_id: PageId,
+        pages: &mut [&mut [u8]],
+    ) -> Result<usize, MyError> {
+        for data in pages.iter() {
+            check_page_len(data, self.page_size)?;
+        }
+        let end = {
+            let state = self.state.lock().unwrap();
+            state.superblock.next_page_id.min(state.file_pages)
+        };
+        let count = end
+            .saturating_sub(first_page_id.to_u64())
+            .min(pages.len() as u64) as usize;
+        if count == 0 {
+            return Ok(0);
+        }
+        let mut buf = vec![0u8; count * self.page_size];
+        let offset = self.page_size as u64 * first_page_id.to_u64();
+        self.heap_file.read_exact_at(&mut buf, offset)?;
+        for (i, (data, chunk)) in pages.iter_mut().zip(buf.chunks(self.page_size)).enumerate() {
+            data.copy_from_slice(chunk);
+            self.counters.record_read(self.page_size);
+            verify_page_checksum(PageId(first_page_id.to_u64() + i as u64), data)?;
+            if is_free_page(data) {
+                return Ok(i);
+            }
+        }
+        Ok(count)
+    }
+
+    pub fn write_page_data(&self, page_id: PageId, data: &mut [u8]) -> Result<(), MyError> {
+        self.write_pages(&mut [(page_id, data)])
+    }
+
+    // 複数のページをまとめて書き込む (二重書き込みの同期をまとめられる)
+    pub fn write_pages(&self, pages: &mut [(PageId, &mut [u8])]) -> Result<(), MyError> {
+        self.check_writable()?;
+        for (page_id, data) in pages.iter_mut() {
+            check_page_len(data, self.page_size)?;
+            // 末尾にチェックサムを書き込む
+            stamp_page_checksum(*page_id, data);
+        }
+        // 二重書き込み領域があれば、どのモードでも先にそこへ書いて永続化する
+        if self.double_write_slots > 0 {
+            for batch in pages.chunks(self.double_write_slots as usize) {
+                self.double_write(batch)?;
+            }
+            return Ok(());
+        }
+        for (page_id, data) in pages.iter() {
+            self.write_raw_page(*page_id, data)?;
+        }
+        if self.options.durability == DurabilityMode::Full {
+            self.sync_heap_file(true)?;
+        }
+        Ok(())
+    }
+
+    fn write_raw_page(&self, page_id: PageId, data: &[u8]) -> std::io::Result<()> {
+        let offset = self.page_size as u64 * page_id.to_u64();
+        self.heap_file.write_all_at(data, offset)?;
+        self.counters.record_write(data.len());
+        Ok(())
+    }
+
+    fn sync_heap_file(&self, data_only: bool) -> std::io::Result<()> {
+        if data_only {
+            self.heap_file.sync_data()?;
+        } else {
+            self.heap_file.sync_all()?;
+        }
+        self.counters.record_sync();
+        Ok(())
+    }
+
+    pub fn stats(&self) -> DiskStats {
+        self.counters.snapshot()
+    }
+
+    pub fn reset_stats(&self) {
+        self.counters.reset();
+    }
+
+    // 書き込みの途中でクラッシュしてもページが壊れたままにならないよう、
+    // 先に二重書き込み領域へ書いて永続化してから本来の位置へ書き込む
+    fn double_write(&self, pages: &[(PageId, &mut [u8])]) -> Result<(), MyError> {
+        let _guard = self.double_write.lock().unwrap();
+        let mut header = vec![0u8; self.page_size];
+        header[DW_MAGIC..DW_MAGIC + DOUBLE_WRITE_MAGIC.len()].copy_from_slice(&DOUBLE_WRITE_MAGIC);
+        header[DW_COUNT..DW_COUNT + 4].copy_from_slice(&(pages.len() as u32).to_le_bytes());
+        for (i, (page_id, data)) in pages.iter().enumerate() {
+            write_page_id(&mut header, DW_PAGE_IDS + i * 8, *page_id);
+            let slot = PageId(DOUBLE_WRITE_HEADER_PAGE_ID.to_u64() + 1 + i as u64);
+            self.write_raw_page(slot, data)?;
+        }
+        stamp_page_checksum(DOUBLE_WRITE_HEADER_PAGE_ID, &mut header);
+        self.write_raw_page(DOUBLE_WRITE_HEADER_PAGE_ID, &header)?;
+        self.sync_heap_file(true)?;
+        // 次の書き込みで二重書き込み領域を上書きする前に、本来の位置への書き込みも永続化しておく
+        for (page_id, data) in pages {
+            self.write_raw_page(*page_id, data)?;
+        }
+        self.sync_heap_file(true)?;
+        // 本来の位置が永続化されたので、複製はもう要らない
+        // 残しておくと、後で二重書き込みせずに書いたページが壊れたときに古い内容で戻してしまう
+        self.write_raw_page(
+            DOUBLE_WRITE_HEADER_PAGE_ID,
+            &empty_double_write_header(self.page_size),
+        )?;
+        Ok(())
+    }
+
+    // これまでに書き込んだ内容をストレージに永続化する
+    pub fn sync(&self) -> Result<(), MyError> {
+        self.sync_heap_file(false)?;
+        Ok(())
+    }
+
+    pub fn durability(&self) -> DurabilityMode {
+        self.options.durability
+    }
+
+    pub fn open(heap_file_path: impl AsRef<Path>) -> Result<Self, MyError> {
+        Self::open_with_options(heap_file_path, DiskManagerOptions::default())
+    }
+
+    pub fn open_with_options(
+        heap_file_path: impl AsRef<Path>,
+        options: DiskManagerOptions,
+    ) -> Result<Self, MyError> {
+        // ヒープファイルはセグメントに分割して `<path>.0`, `<path>.1`, ... に置く
+        let heap_file_path = heap_file_path.as_ref();
+        let first_segment = OpenOptions::new()
+            .read(true)
+            .write(!options.read_only)
+            .create(!options.read_only)
+            .truncate(false)
+            .open(segment_path(heap_file_path, 0))?;
+        // 他のプロセスが同時に書き込んで壊さないよう、アドバイザリロックを取る
+        // ロックはファイルを閉じる (DiskManager を drop する) と解放される
+        let locked = if options.read_only {
+            first_segment.try_lock_shared()
+        } else {
+            first_segment.try_lock()
+        };
+        match locked {
+            Ok(()) => {}
+            Err(TryLockError::WouldBlock) => return Err(MyError::DatabaseLocked),
+            Err(TryLockError::Error(e)) => return Err(e.into()),
+        }
+        // 既存のデータベースならセグメントの大きさはスーパーブロックに記録されたものを使う
+        let mut segment_size = options.segment_size;
+        if first_segment.metadata()?.len() >= SB_HEADER_SIZE as u64 {
+            let mut header = [0u8; SB_HEADER_SIZE];
+            first_segment.read_exact_at(&mut header, 0)?;
+            segment_size = Superblock::decode_header(&header)?.segment_size;
+        }
+        let heap_file = SegmentedFile::open(
+            heap_file_path,
+            first_segment,
+            segment_size,
+            options.read_only,
+        )?;
+        Self::with_options(heap_file, options)
+    }
+
+    pub fn allocate_page(&self) -> Result<PageId, MyError> {
+        self.check_writable()?;
+        let mut state = self.state.lock().unwrap();
+        // 解放済みページがあれば再利用する
+        if let Some(page_id) = state.superblock.free_list_head.valid() {
+            let mut data = vec![0u8; self.page_size];
+            self.read_page_data(page_id, &mut data)?;
+            if !is_free_page(&data) {
+                return Err(corrupted_free_list(page_id));
+            }
+            state.superblock.free_list_head = read_page_id(&data, FREE_PAGE_NEXT);
+            self.write_superblock(&state.superblock)?;
+            // 解放済みの印を消しておく (先に消すと、途中で失敗したときにフリーリストが壊れる)
+            data.fill(0);
+            self.write_page_data(page_id, &mut data)?;
+            return Ok(page_id);
+        }
+        let page_id = state.superblock.next_page_id;
+        // ファイル末尾を越える場合はまとめて拡張しておき、すぐに読み書きできるようにする
+        if page_id >= state.file_pages {
+            let file_pages = page_id + self.options.growth_pages;
+            self.heap_file.set_len(file_pages * self.page_size as u64)?;
+            state.file_pages = file_pages;
+        }
+        state.superblock.next_page_id += 1;
+        self.write_superblock(&state.superblock)?;
+        Ok(PageId(page_id))
+    }
+
+    pub fn deallocate_page(&self, page_id: PageId) -> Result<(), MyError> {
+        self.check_writable()?;
+        let mut state = self.state.lock().unwrap();
+        if page_id < state.superblock.first_data_page_id()
+            || page_id.to_u64() >= state.superblock.next_page_id
+        {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("cannot deallocate page {}", page_id.to_u64()),
+            )
+            .into());
+        }
+        // 既に解放済みのページをもう一度繋ぐと、リストが循環して同じページが何度も採番される
+        let mut data = vec![0u8; self.page_size];
+        match self.read_page_data(page_id, &mut data) {
+            Ok(()) if is_free_page(&data) => return Err(MyError::PageAlreadyFree(page_id)),
+            // 壊れたページも解放はできる
+            Ok(()) | Err(MyError::PageCorrupted { .. }) => {}
+            Err(e) => return Err(e),
+        }
+        // 解放するページ自体にフリーリストの次のページIDを書き込み、先頭に繋ぐ
+        encode_free_page(&mut data, state.superblock.free_list_head);
+        self.write_page_data(page_id, &mut data)?;
+        state.superblock.free_list_head = page_id;
+        self.write_superblock(&state.superblock)
+    }
+
+    // index は CATALOG_ROOT_COUNT 未満であること
+    pub fn catalog_root(&self, index: usize) -> Result<PageId, MyError> {
+        check_catalog_root_index(index)?;
+        Ok(self.state.lock().unwrap().superblock.catalog_roots[index])
+    }
+
+    pub fn set_catalog_root(&self, index: usize, page_id: PageId) -> Result<(), MyError> {
+        check_catalog_root_index(index)?;
+        self.check_writable()?;
+        let mut state = self.state.lock().unwrap();
+        state.superblock.catalog_roots[index] = page_id;
+        self.write_superblock(&state.superblock)
+    }
+
+    // 生きているページをファイルの前方の空きページへ移動し、末尾の空きページを切り詰める
+    // 移動したページを指す参照は rewriter で書き換える (カタログのルートはここで書き換える)
+    // どこでクラッシュしても、生きているページを採番したり切り詰めた範囲を参照したりしないよう、
+    // 移動先をフリーリストから外す、複製する、参照を書き換える、切り詰める、の順に一段ずつ永続化する
+    // 参照の書き換えの途中でクラッシュした場合は、移動元と移動先が同じ内容のまま両方とも残る
+    pub fn compact(&self, rewriter: &mut ReferenceRewriter) -> Result<Relocations, MyError> {
+        self.check_writable()?;
+        let mut state = self.state.lock().unwrap();
+        let first_page_id = state.superblock.first_data_page_id();
+        let next_page_id = state.superblock.next_page_id;
+        // フリーリストをたどって空きページを集める
+        let mut free_pages = vec![];
+        let mut data = vec![0u8; self.page_size];
+        let mut cursor = state.superblock.free_list_head;
+        while let Some(page_id) = cursor.valid() {
+            if free_pages.len() as u64 >= next_page_id {
+                return Err(Error::new(ErrorKind::InvalidData, "free list has a cycle").into());
+            }
+            self.read_page_data(page_id, &mut data)?;
+            if !is_free_page(&data) {
+                return Err(corrupted_free_list(page_id));
+            }
+            free_pages.push(page_id);
+            cursor = read_page_id(&data, FREE_PAGE_NEXT);
+        }
+        free_pages.sort();
+        let live_pages: Vec<PageId> = (first_page_id.to_u64()..next_page_id)
+            .map(PageId)
+            .filter(|page_id| free_pages.binary_search(page_id).is_err())
+            .collect();
+        let (moves, new_next_page_id) = plan_compaction(&live_pages, &free_pages, first_page_id);
+
+        // 移動先は前から順に使うので、残りの空きページだけでフリーリストを作り直す
+        // 複製する前に永続化しておけば、移動先が空きページとして採番されることはない
+        if !moves.is_empty() {
+            let remaining = &free_pages[moves.len()..];
+            for (i, &page_id) in remaining.iter().enumerate() {
+                let next = remaining
+                    .get(i + 1)
+                    .copied()
+                    .unwrap_or(PageId::INVALID_PAGE_ID);
+                encode_free_page(&mut data, next);
+                self.write_page_data(page_id, &mut data)?;
+            }
+            self.sync_heap_file(true)?;
+            state.superblock.free_list_head = remaining
+                .first()
+                .copied()
+                .unwrap_or(PageId::INVALID_PAGE_ID);
+            self.write_superblock(&state.superblock)?;
+            self.sync_heap_file(true)?;
+        }
+
+        // ページを移動先へ複製する (チェックサムは移動先のページIDで計算し直される)
+        for &(from, to) in &moves {
+            self.read_page_data(from, &mut data)?;
+            self.write_page_data(to, &mut data)?;
+        }
+        self.sync_heap_file(true)?;
+
+        // 移動したページを指している参照を書き換える
+        // 詰めた後の範囲には生きているページしか残っていない
+        let relocations: Relocations = moves.into_iter().collect();
+        if !relocations.is_empty() {
+            for page_id in (first_page_id.to_u64()..new_next_page_id).map(PageId) {
+                self.read_page_data(page_id, &mut data)?;
+                if rewriter(page_id, &mut data, &relocations) {
+                    self.write_page_data(page_id, &mut data)?;
+                }
+            }
+            for root in state.superblock.catalog_roots.iter_mut() {
+                if let Some(&new_page_id) = relocations.get(root) {
+                    *root = new_page_id;
+                }
+            }
+            self.write_superblock(&state.superblock)?;
+            self.sync_heap_file(true)?;
+        }
+
+        // 空きページはすべて末尾に寄ったので、フリーリストを空にしてから切り詰める
+        state.superblock.free_list_head = PageId::INVALID_PAGE_ID;
+        state.superblock.next_page_id = new_next_page_id;
+        self.write_superblock(&state.superblock)?;
+        self.sync_heap_file(true)?;
+        self.heap_file
+            .set_len(new_next_page_id * self.page_size as u64)?;
+        state.file_pages = new_next_page_id;
+        self.sync_heap_file(false)?;
+        Ok(relocations)
+    }
+
+    // 開いていないデータベースファイルを compact する
+    pub fn compact_file(
+        heap_file_path: impl AsRef<Path>,
+        options: DiskManagerOptions,
+        rewriter: &mut ReferenceRewriter,
+    ) -> Result<Relocations, MyError> {
+        let disk = Self::open_with_options(heap_file_path, options)?;
+        disk.compact(rewriter)
+    }
+
+    fn check_writable(&self) -> Result<(), MyError> {
+        if self.options.read_only {
+            return Err(MyError::ReadOnly);
+        }
+        Ok(())
+    }
+
+    fn write_superblock(&self, superblock: &Superblock) -> Result<(), MyError> {
+        let mut data = vec![0u8; self.page_size];
+        superblock.encode(&mut data);
+        self.write_page_data(SUPERBLOCK_PAGE_ID, &mut data)
+    }
+}
+
+fn empty_double_write_header(page_size: usize) -> Vec<u8> {
+    let mut header = vec![0u8; page_size];
+    header[DW_MAGIC..DW_MAGIC + DOUBLE_WRITE_MAGIC.len()].copy_from_slice(&DOUBLE_WRITE_MAGIC);
+    stamp_page_checksum(DOUBLE_WRITE_HEADER_PAGE_ID, &mut header);
+    header
+}
+
+// 二重書き込み領域に残っているページのうち、本来の位置で壊れているものを書き戻す
+// 書き戻した後は二重書き込み領域を空にして、書き戻したページの数を返す
+fn recover_double_write(heap_file: &dyn DiskFile, page_size: usize) -> Result<usize, MyError> {
+    let file_size = heap_file.size()?;
+    let page_offset = |page_id: PageId| page_id.to_u64() * page_size as u64;
+    if file_size < page_offset(DOUBLE_WRITE_HEADER_PAGE_ID) + page_size as u64 {
+        return Ok(0);
+    }
+    let mut header = vec![0u8; page_size];
+    heap_file.read_exact_at(&mut header, page_offset(DOUBLE_WRITE_HEADER_PAGE_ID))?;
+    if header[DW_MAGIC..DW_MAGIC + DOUBLE_WRITE_MAGIC.len()] != DOUBLE_WRITE_MAGIC
+        || verify_page_checksum(DOUBLE_WRITE_HEADER_PAGE_ID, &header).is_err()
+    {
+        return Ok(0);
+    }
+    let count = read_u32(&header, DW_COUNT).min(max_double_write_slots(page_size));
+    let mut slot_data = vec![0u8; page_size];
+    let mut home_data = vec![0u8; page_size];
+    let mut restored = 0;
+    for i in 0..count as usize {
+        let page_id = read_page_id(&header, DW_PAGE_IDS + i * 8);
+        let slot = PageId(DOUBLE_WRITE_HEADER_PAGE_ID.to_u64() + 1 + i as u64);
+        heap_file.read_exact_at(&mut slot_data, page_offset(slot))?;
+        // 二重書き込み領域への書き込みが途切れていたなら、本来の位置はまだ書き換えられていない
+        if verify_page_checksum(page_id, &slot_data).is_err() {
+            continue;
+        }
+        let home_intact = page_offset(page_id) + page_size as u64 <= file_size
+            && heap_file
+                .read_exact_at(&mut home_data, page_offset(page_id))
+                .is_ok()
+            && verify_page_checksum(page_id, &home_data).is_ok();
+        if !home_intact {
+            heap_file.write_all_at(&slot_data, page_offset(page_id))?;
+            restored += 1;
+        }
+    }
+    if count > 0 {
+        heap_file.sync_all()?;
+        heap_file.write_all_at(
+            &empty_double_write_header(page_size),
+            page_offset(DOUBLE_WRITE_HEADER_PAGE_ID),
+        )?;
+        heap_file.sync_all()?;
+    }
+    Ok(restored)
+}
+
+impl PageStore for DiskManager {
+    fn page_size(&self) -> usize {
+        DiskManager::page_size(self)
+    }
+
+    fn read_page_data(&self, page_id: PageId, data: &mut [u8]) -> Result<(), MyError> {
+        DiskManager::read_page_data(self, page_id, data)
+    }
+
+    fn write_page_data(&self, page_id: PageId, data: &mut [u8]) -> Result<(), MyError> {
+        DiskManager::write_page_data(self, page_id, data)
+    }
+
+    fn read_pages(&self, first_page_id: PageId, pages: &mut [&mut [u8]]) -> Result<usize, MyError> {
+        DiskManager::read_pages(self, first_page_id, pages)
+    }
+
+    fn write_pages(&self, pages: &mut [(PageId, &mut [u8])]) -> Result<(), MyError> {
+        DiskManager::write_pages(self, pages)
+    }
+
+    fn allocate_page(&self) -> Result<PageId, MyError> {
+        DiskManager::allocate_page(self)
+    }
+
+    fn deallocate_page(&self, page_id: PageId) -> Result<(), MyError> {
+        DiskManager::deallocate_page(self, page_id)
+    }
+
+    fn sync(&self) -> Result<(), MyError> {
+        DiskManager::sync(self)
+    }
+
+    fn durability(&self) -> DurabilityMode {
+        DiskManager::durability(self)
+    }
+
+    fn compact(&self, rewriter: &mut ReferenceRewriter) -> Result<Relocations, MyError> {
+        DiskManager::compact(self, rewriter)
+    }
+
+    fn stats(&self) -> DiskStats {
+        DiskManager::stats(self)
+    }
+
+    fn reset_stats(&self) {
+        DiskManager::reset_stats(self)
+    }
+}
+
+// DiskManager が読み書きするファイル (テストでは障害を注入する実装に差し替えられる)
+pub trait DiskFile: Send + Sync {
+    fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> std::io::Result<()>;
+    fn write_all_at(&self, buf: &[u8], offset: u64) -> std::io::Result<()>;
+    fn size(&self) -> std::io::Result<u64>;
+    fn set_len(&self, size: u64) -> std::io::Result<()>;
+    fn sync_data(&self) -> std::io::Result<()>;
+    fn sync_all(&self) -> std::io::Result<()>;
+}
+
+// 位置指定でファイルを読み書きする (ファイルのシーク位置を共有しないので &File で足りる)
+impl DiskFile for File {
+    #[cfg(unix)]
+    fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> std::io::Result<()> {
+        std::os::unix::fs::FileExt::read_exact_at(self, buf, offset)
+    }
+
+    #[cfg(unix)]
+    fn write_all_at(&self, buf: &[u8], offset: u64) -> std::io::Result<()> {
+        std::os::unix::fs::FileExt::write_all_at(self, buf, offset)
+    }
+
+    #[cfg(windows)]
+    fn read_exact_at(&self, mut buf: &mut [u8], mut offset: u64) -> std::io::Result<()> {
+        use std::os::windows::fs::FileExt;
+        while !buf.is_empty() {
+            match self.seek_read(buf, offset) {
+                Ok(0) => return Err(Error::from(ErrorKind::UnexpectedEof)),
+                Ok(n) => {
+                    buf = &mut buf[n..];
+                    offset += n as u64;
+                }
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    #[cfg(windows)]
+    fn write_all_at(&self, mut buf: &[u8], mut offset: u64) -> std::io::Result<()> {
+        use std::os::windows::fs::FileExt;
+        while !buf.is_empty() {
+            match self.seek_write(buf, offset) {
+                Ok(0) => return Err(Error::from(ErrorKind::WriteZero)),
+                Ok(n) => {
+                    buf = &buf[n..];
+                    offset += n as u64;
+                }
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    fn size(&self) -> std::io::Result<u64> {
+        Ok(self.metadata()?.len())
+    }
+
+    fn set_len(&self, size: u64) -> std::io::Result<()> {
+        File::set_len(self, size)
+    }
+
+    fn sync_data(&self) -> std::io::Result<()> {
+        File::sync_data(self)
+    }
+
+    fn sync_all(&self) -> std::io::Result<()> {
+        File::sync_all(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::buffer::{BufferPool, BufferPoolManager};
+    use crate::fault::FaultInjector;
+
+    // テストごとに一時ディレクトリを作り、終わったら消す
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!(
+                "rust_mini_rdbms-{}-{}",
+                std::process::id(),
+                name
+            ));
+            let _ = fs::remove_dir_all(&path);
+            fs::create_dir_all(&path).unwrap();
+            Self(path)
+        }
+
+        fn path(&self, file_name: &str) -> PathBuf {
+            self.0.join(file_name)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn open(injector: &FaultInjector) -> DiskManager {
+        DiskManager::with_options(injector.file(), DiskManagerOptions::default()).unwrap()
+    }
+
+    fn open_with_durability(injector: &FaultInjector, durability: DurabilityMode) -> DiskManager {
+        let options = DiskManagerOptions {
+            durability,
+            ..Default::default()
+        };
+        DiskManager::with_options(injector.file(), options).unwrap()
+    }
+
+    fn filled_page(disk: &DiskManager, byte: u8) -> Vec<u8> {
+        vec![byte; disk.page_size()]
+    }
+
+    fn read_first_byte(disk: &DiskManager, page_id: PageId) -> Result<u8, MyError> {
+        let mut data = vec![0u8; disk.page_size()];
+        disk.read_page_data(page_id, &mut data)?;
+        Ok(data[0])
+    }
+
+    #[test]
+    fn free_list_survives_reopen() {
+        let injector = FaultInjector::new();
+        let disk = open(&injector);
+        let page_ids: Vec<PageId> = (0..4).map(|_| disk.allocate_page().unwrap()).collect();
+        disk.deallocate_page(page_ids[1]).unwrap();
+        disk.deallocate_page(page_ids[3]).unwrap();
+        drop(disk);
+
+        let disk = open(&injector);
+        // 後から解放したページから再利用され、中身はゼロに戻っている
+        assert_eq!(disk.allocate_page().unwrap(), page_ids[3]);
+        assert_eq!(disk.allocate_page().unwrap(), page_ids[1]);
+        let mut data = vec![0xffu8; disk.page_size()];
+        disk.read_page_data(page_ids[1], &mut data).unwrap();
+        assert!(data[..disk.page_size() - PAGE_TRAILER_SIZE]
+            .iter()
+            .all(|&b| b == 0));
+        // フリーリストが空になったら末尾から採番する
+        assert_eq!(
+            disk.allocate_page().unwrap(),
+            PageId(page_ids[3].to_u64() + 1)
+        );
+    }
+
+    #[test]
+    fn double_free_is_rejected() {
+        let injector = FaultInjector::new();
+        let disk = open(&injector);
+        let page_ids: Vec<PageId> = (0..2).map(|_| disk.allocate_page().unwrap()).collect();
+        disk.deallocate_page(page_ids[0]).unwrap();
+        assert!(matches!(
+            disk.deallocate_page(page_ids[0]),
+            Err(MyError::PageAlreadyFree(page_id)) if page_id == page_ids[0]
+        ));
+        let first = disk.allocate_page().unwrap();
+        let second = disk.allocate_page().unwrap();
+        assert_eq!(first, page_ids[0]);
+        assert_ne!(first, second);
+        assert_ne!(second, page_ids[1]);
+        // 再利用したページはもう一度解放できる
+        disk.deallocate_page(first).unwrap();
+    }
+
+    #[test]
+    fn catalog_roots_survive_reopen() {
+        let injector = FaultInjector::new();
+        let disk = open(&injector);
+        let page_id = disk.allocate_page().unwrap();
+        disk.set_catalog_root(CATALOG_ROOT_COUNT - 1, page_id)
+            .unwrap();
+        assert!(disk.catalog_root(CATALOG_ROOT_COUNT).is_err());
+        assert!(disk.set_catalog_root(CATALOG_ROOT_COUNT, page_id).is_err());
+        drop(disk);
+
+        let disk = open(&injector);
+        assert_eq!(disk.catalog_root(CATALOG_ROOT_COUNT - 1).unwrap(), page_id);
+        assert_eq!(disk.catalog_root(0).unwrap(), PageId::INVALID_PAGE_ID);
+    }
+
+    #[test]
+    fn file_without_magic_is_not_a_database() {
+        let injector = FaultInjector::new();
+        injector
+            .file()
+            .write_all_at(&vec![b'x'; DEFAULT_PAGE_SIZE], 0)
+            .unwrap();
+        assert!(matches!(
+            DiskManager::with_options(injector.file(), DiskManagerOptions::default()),
+            Err(MyError::NotADatabase)
+        ));
+        // スーパーブロックの先頭部分より短いファイル
+        let injector = FaultInjector::new();
+        injector.file().write_all_at(&MAGIC, 0).unwrap();
+        assert!(matches!(
+            DiskManager::with_options(injector.file(), DiskManagerOptions::default()),
+            Err(MyError::NotADatabase)
+        ));
+    }
+
+    #[test]
+    fn newer_format_version_is_rejected() {
+        let injector = FaultInjector::new();
+        drop(open(&injector));
+        injector
+            .file()
+            .write_all_at(
+                &(FORMAT_VERSION + 1).to_le_bytes(),
+                SB_FORMAT_VERSION as u64,
+            )
+            .unwrap();
+        assert!(matches!(
+            DiskManager::with_options(injector.file(), DiskManagerOptions::default()),
+            Err(MyError::UnsupportedFormatVersion { found, supported })
+                if found == FORMAT_VERSION + 1 && supported == FORMAT_VERSION
+        ));
+    }
+
+    #[test]
+    fn corrupted_superblock_is_rejected() {
+        let injector = FaultInjector::new();
+        drop(open(&injector));
+        injector.flip_bit_on_read(SB_NEXT_PAGE_ID as u64, 3);
+        assert!(matches!(
+            DiskManager::with_options(injector.file(), DiskManagerOptions::default()),
+            Err(MyError::PageCorrupted { page_id, .. }) if page_id == SUPERBLOCK_PAGE_ID
+        ));
+        injector.clear_faults();
+        open(&injector);
+    }
+
+    #[test]
+    fn flipped_bit_is_reported_as_corruption() {
+        let injector = FaultInjector::new();
+        let disk = open(&injector);
+        let page_id = disk.allocate_page().unwrap();
+        disk.write_page_data(page_id, &mut filled_page(&disk, 7))
+            .unwrap();
+        let offset = disk.page_size() as u64 * page_id.to_u64();
+        // 本体とチェックサムのどちらが壊れても検出する
+        for corrupted in [offset + 100, offset + disk.page_size() as u64 - 1] {
+            injector.flip_bit_on_read(corrupted, 5);
+            assert!(matches!(
+                read_first_byte(&disk, page_id),
+                Err(MyError::PageCorrupted { page_id: id, expected, actual })
+                    if id == page_id && expected != actual
+            ));
+            injector.clear_faults();
+        }
+        assert_eq!(read_first_byte(&disk, page_id).unwrap(), 7);
+    }
+
+    #[test]
+    fn page_written_to_the_wrong_location_is_detected() {
+        let injector = FaultInjector::new();
+        let disk = open(&injector);
+        let from = disk.allocate_page().unwrap();
+        let to = disk.allocate_page().unwrap();
+        let mut data = filled_page(&disk, 3);
+        disk.write_page_data(from, &mut data).unwrap();
+        // チェックサムごと別の位置にコピーされたページ
+        disk.write_raw_page(to, &data).unwrap();
+        assert!(matches!(
+            read_first_byte(&disk, to),
+            Err(MyError::PageCorrupted { page_id, .. }) if page_id == to
+        ));
+        assert_eq!(read_first_byte(&disk, from).unwrap(), 3);
+    }
+
+    #[test]
+    fn double_write_is_used_in_every_mode() {
+        let injector = FaultInjector::new();
+        let page_id = open(&injector).allocate_page().unwrap();
+        for durability in [
+            DurabilityMode::Off,
+            DurabilityMode::Normal,
+            DurabilityMode::Full,
+        ] {
+            let disk = open_with_durability(&injector, durability);
+            disk.reset_stats();
+            disk.write_page_data(page_id, &mut filled_page(&disk, 1))
+                .unwrap();
+            // 複製、ヘッダ、本来の位置、ヘッダの片付けの 4 回
+            assert_eq!(disk.stats().syncs, 2, "{:?}", durability);
+            assert_eq!(disk.stats().pages_written, 4, "{:?}", durability);
+        }
+
+        // 二重書き込み領域のないファイルでは、そのまま書き込む
+        let injector = FaultInjector::new();
+        let options = DiskManagerOptions {
+            double_write_slots: 0,
+            ..Default::default()
+        };
+        let disk = DiskManager::with_options(injector.file(), options).unwrap();
+        let page_id = disk.allocate_page().unwrap();
+        disk.reset_stats();
+        disk.write_page_data(page_id, &mut filled_page(&disk, 1))
+            .unwrap();
+        assert_eq!(disk.stats().syncs, 0);
+        assert_eq!(disk.stats().pages_written, 1);
+    }
+
+    #[test]
+    fn torn_page_is_restored_from_double_write() {
+        for durability in [DurabilityMode::Normal, DurabilityMode::Full] {
+            let injector = FaultInjector::new();
+            let disk = open_with_durability(&injector, durability);
+            let page_id = disk.allocate_page().unwrap();
+            disk.write_page_data(page_id, &mut filled_page(&disk, 1))
+                .unwrap();
+            // 複製とヘッダを書いて永続化した後、本来の位置への書き込みが途中で途切れる
+            // その後の sync で途切れた内容が永続化され、ヘッダを片付ける前にクラッシュする
+            injector.tear_nth_write(3, 100);
+            injector.fail_nth_write(4);
+            assert!(disk
+                .write_page_data(page_id, &mut filled_page(&disk, 2))
+                .is_err());
+            injector.crash();
+            drop(disk);
+
+            let disk = open(&injector);
+            assert_eq!(
+                read_first_byte(&disk, page_id).unwrap(),
+                2,
+                "{:?}",
+                durability
+            );
+        }
+    }
+
+    #[test]
+    fn torn_double_write_copy_keeps_the_old_page() {
+        let injector = FaultInjector::new();
+        let disk = open_with_durability(&injector, DurabilityMode::Full);
+        let page_id = disk.allocate_page().unwrap();
+        disk.write_page_data(page_id, &mut filled_page(&disk, 1))
+            .unwrap();
+        // 複製が途切れたまま永続化され、本来の位置に書く前にクラッシュする
+        injector.tear_nth_write(1, 100);
+        injector.fail_nth_write(3);
+        assert!(disk
+            .write_page_data(page_id, &mut filled_page(&disk, 2))
+            .is_err());
+        injector.crash();
+        drop(disk);
+
+        let disk = open(&injector);
+        assert_eq!(read_first_byte(&disk, page_id).unwrap(), 1);
+    }
+
+    #[test]
+    fn stale_double_write_copy_is_not_restored() {
+        let injector = FaultInjector::new();
+        let disk = open_with_durability(&injector, DurabilityMode::Full);
+        let page_id = disk.allocate_page().unwrap();
+        disk.write_page_data(page_id, &mut filled_page(&disk, 1))
+            .unwrap();
+        let offset = page_id.to_u64() * disk.page_size() as u64;
+        drop(disk);
+
+        // 二重書き込みを経ずに途切れたページを、片付け済みの前の複製で戻したりしない
+        let file = injector.file();
+        file.write_all_at(&[2u8; 100], offset).unwrap();
+        file.sync_data().unwrap();
+        injector.crash();
+
+        let disk = open(&injector);
+        assert!(matches!(
+            read_first_byte(&disk, page_id),
+            Err(MyError::PageCorrupted { .. })
+        ));
+    }
+
+    // compact のテスト用のデータベース
+    // カタログのルート 0 が指すページに、生きているデータページのIDとタグを並べておく
+    // ルートページ: [0] = ROOT_MARK, [1] = 数, [8 + 16 * i..) = ページID, [16 + 16 * i] = タグ
+    // データページ: [0] = タグ
+    const ROOT_MARK: u8 = 0xee;
+
+    fn write_byte_page(disk: &DiskManager, page_id: PageId, fill: impl FnOnce(&mut [u8])) {
+        let mut data = vec![0u8; disk.page_size()];
+        fill(&mut data);
+        disk.write_page_data(page_id, &mut data).unwrap();
+    }
+
+    fn setup_compaction(disk: &DiskManager) {
+        let data_pages: Vec<PageId> = (0..6).map(|_| disk.allocate_page().unwrap()).collect();
+        // ルートは最後に採番するので、前の空きへ移動される
+        let root = disk.allocate_page().unwrap();
+        let mut live = vec![];
+        for (i, &page_id) in data_pages.iter().enumerate() {
+            let tag = i as u8 + 1;
+            write_byte_page(disk, page_id, |data| data[0] = tag);
+            if i % 2 == 1 && i < 4 {
+                disk.deallocate_page(page_id).unwrap();
+            } else {
+                live.push((page_id, tag));
+            }
+        }
+        write_byte_page(disk, root, |data| {
+            data[0] = ROOT_MARK;
+            data[1] = live.len() as u8;
+            for (i, &(page_id, tag)) in live.iter().enumerate() {
+                write_page_id(data, 8 + 16 * i, page_id);
+                data[16 + 16 * i] = tag;
+            }
+        });
+        disk.set_catalog_root(0, root).unwrap();
+        disk.sync().unwrap();
+    }
+
+    fn rewrite_root(_page_id: PageId, data: &mut [u8], relocations: &Relocations) -> bool {
+        if data[0] != ROOT_MARK {
+            return false;
+        }
+        for i in 0..data[1] as usize {
+            let page_id = read_page_id(data, 8 + 16 * i);
+            if let Some(&new_page_id) = relocations.get(&page_id) {
+                write_page_id(data, 8 + 16 * i, new_page_id);
+            }
+        }
+        true
+    }
+
+    fn free_list(disk: &DiskManager) -> Vec<PageId> {
+        let mut free_pages = vec![];
+        let mut data = vec![0u8; disk.page_size()];
+        let mut cursor = disk.state.lock().unwrap().superblock.free_list_head;
+        while let Some(page_id) = cursor.valid() {
+            disk.read_page_data(page_id, &mut data).unwrap();
+            assert!(is_free_page(&data), "page {} is not free", page_id.to_u64());
+            assert!(!free_pages.contains(&page_id));
+            free_pages.push(page_id);
+            cursor = read_page_id(&data, FREE_PAGE_NEXT);
+        }
+        free_pages
+    }
+
+    // ルートから参照されているページが元のタグを持ち、どれもフリーリストに入っていないことを確かめる
+    fn assert_compaction_consistent(disk: &DiskManager) -> Vec<PageId> {
+        let root = disk.catalog_root(0).unwrap();
+        let mut data = vec![0u8; disk.page_size()];
+        disk.read_page_data(root, &mut data).unwrap();
+        assert_eq!(data[0], ROOT_MARK);
+        let mut referenced = vec![root];
+        let mut page = vec![0u8; disk.page_size()];
+        for i in 0..data[1] as usize {
+            let page_id = read_page_id(&data, 8 + 16 * i);
+            disk.read_page_data(page_id, &mut page).unwrap();
+            assert_eq!(page[0], data[16 + 16 * i]);
+            referenced.push(page_id);
+        }
+        let free_pages = free_list(disk);
+        for page_id in &referenced {
+            assert!(!free_pages.contains(page_id));
+        }
+        referenced
+    }
+
+    #[test]
+    fn compact_moves_live_pages_and_truncates() {
+        let injector = FaultInjector::new();
+        let disk = open(&injector);
+        setup_compaction(&disk);
+        let old_root = disk.catalog_root(0).unwrap();
+        let relocations = disk.compact(&mut rewrite_root).unwrap();
+        assert_eq!(relocations.len(), 2);
+        assert!(relocations.contains_key(&old_root));
+        assert_ne!(disk.catalog_root(0).unwrap(), old_root);
+        let referenced = assert_compaction_consistent(&disk);
+        let next_page_id = referenced.iter().max().unwrap().to_u64() + 1;
+        assert_eq!(
+            injector.file().size().unwrap(),
+            next_page_id * disk.page_size() as u64
+        );
+        assert!(free_list(&disk).is_empty());
+        drop(disk);
+
+        let disk = open(&injector);
+        assert_compaction_consistent(&disk);
+        assert_eq!(disk.allocate_page().unwrap(), PageId(next_page_id));
+    }
+
+    #[test]
+    fn compact_file_compacts_a_closed_database() {
+        let dir = TempDir::new("compact_file");
+        let path = dir.path("test.btr");
+        let disk = DiskManager::open(&path).unwrap();
+        setup_compaction(&disk);
+        drop(disk);
+
+        let relocations =
+            DiskManager::compact_file(&path, DiskManagerOptions::default(), &mut rewrite_root)
+                .unwrap();
+        assert_eq!(relocations.len(), 2);
+        let disk = DiskManager::open(&path).unwrap();
+        assert_compaction_consistent(&disk);
+        assert!(free_list(&disk).is_empty());
+    }
+
+    #[test]
+    fn failed_compact_never_frees_live_pages() {
+        for crash in [false, true] {
+            for n in 1.. {
+                if compact_with_failed_write(n, crash) {
+                    assert!(n > 1);
+                    break;
+                }
+            }
+        }
+    }
+
+    // n 回目の書き込みを失敗させて compact し、(crash なら sync していない内容を捨てて) 開き直す
+    // compact が最後まで成功したら true を返す
+    fn compact_with_failed_write(n: u64, crash: bool) -> bool {
+        let injector = FaultInjector::new();
+        let disk = open(&injector);
+        setup_compaction(&disk);
+        injector.fail_nth_write(n);
+        let result = disk.compact(&mut rewrite_root);
+        if crash {
+            injector.crash();
+        }
+        drop(disk);
+
+        let disk = open(&injector);
+        let referenced = assert_compaction_consistent(&disk);
+        // 空きページを採番し尽くしても、参照されているページは出てこない
+        for _ in 0..free_list(&disk).len() {
+            assert!(!referenced.contains(&disk.allocate_page().unwrap()));
+        }
+        result.is_ok()
+    }
+
+    #[test]
+    fn allocated_pages_are_usable_past_the_old_end_of_file() {
+        let injector = FaultInjector::new();
+        let options = DiskManagerOptions {
+            growth_pages: 4,
+            ..Default::default()
+        };
+        let disk = DiskManager::with_options(injector.file(), options).unwrap();
+        let page_size = disk.page_size() as u64;
+        let old_pages = injector.file().size().unwrap() / page_size;
+        let page_ids: Vec<PageId> = (0..6).map(|_| disk.allocate_page().unwrap()).collect();
+        assert_eq!(page_ids[0].to_u64(), old_pages);
+        // growth_pages ずつまとめて拡張する
+        assert_eq!(injector.file().size().unwrap(), (old_pages + 8) * page_size);
+        for &page_id in &page_ids {
+            assert_eq!(read_first_byte(&disk, page_id).unwrap(), 0);
+        }
+
+        // バッファプールからもそのまま読み書きできる
+        let bufmgr = BufferPoolManager::new(Box::new(disk), BufferPool::new(2, DEFAULT_PAGE_SIZE));
+        for &page_id in &page_ids {
+            bufmgr.fetch_page_write(page_id).unwrap()[0] = page_id.to_u64() as u8;
+        }
+        drop(bufmgr);
+        let disk = open(&injector);
+        for &page_id in &page_ids {
+            assert_eq!(
+                read_first_byte(&disk, page_id).unwrap(),
+                page_id.to_u64() as u8
+            );
+        }
+    }
+
+    #[test]
+    fn page_size_is_kept_across_reopen() {
+        for page_size in [8192, MAX_PAGE_SIZE] {
+            let injector = FaultInjector::new();
+            let options = DiskManagerOptions {
+                page_size,
+                ..Default::default()
+            };
+            let disk = DiskManager::with_options(injector.file(), options).unwrap();
+            let page_id = disk.allocate_page().unwrap();
+            let mut data = filled_page(&disk, 5);
+            disk.write_page_data(page_id, &mut data).unwrap();
+            drop(disk);
+
+            // 既存のファイルではオプションではなくスーパーブロックのページサイズを使う
+            let disk = open(&injector);
+            assert_eq!(disk.page_size(), page_size);
+            let bufmgr = BufferPoolManager::new(Box::new(disk), BufferPool::new(2, page_size));
+            let guard = bufmgr.fetch_page_read(page_id).unwrap();
+            assert_eq!(guard.len(), page_size - PAGE_TRAILER_SIZE);
+            assert!(guard.iter().all(|&b| b == 5));
+        }
+    }
+
+    #[test]
+    fn unsupported_page_size_is_rejected() {
+        for page_size in [0, 2048, 6000, MAX_PAGE_SIZE * 2] {
+            let options = DiskManagerOptions {
+                page_size,
+                ..Default::default()
+            };
+            assert!(matches!(
+                DiskManager::with_options(FaultInjector::new().file(), options),
+                Err(MyError::UnsupportedPageSize(size)) if size == page_size as u32
+            ));
+        }
+    }
+
+    #[test]
+    fn concurrent_reads_through_a_shared_reference() {
+        let dir = TempDir::new("concurrent-reads");
+        let disk = DiskManager::open(dir.path("test.btr")).unwrap();
+        let page_ids: Vec<PageId> = (0..16).map(|_| disk.allocate_page().unwrap()).collect();
+        for &page_id in &page_ids {
+            let mut data = filled_page(&disk, page_id.to_u64() as u8);
+            disk.write_page_data(page_id, &mut data).unwrap();
+        }
+        let disk = &disk;
+        std::thread::scope(|s| {
+            for offset in 0..4 {
+                let page_ids = &page_ids;
+                s.spawn(move || {
+                    let mut data = filled_page(disk, 0);
+                    for i in 0..200 {
+                        let page_id = page_ids[(i * 5 + offset) % page_ids.len()];
+                        disk.read_page_data(page_id, &mut data).unwrap();
+                        assert!(data[..disk.usable_page_size()]
+                            .iter()
+                            .all(|&b| b == page_id.to_u64() as u8));
+                    }
+                });
+            }
+        });
+        assert_eq!(disk.stats().pages_read, 800);
+    }
+
+    #[test]
+    fn sync_follows_the_durability_mode() {
+        // (モード, ページを 1 回書き込んだときの sync 回数, flush_all の sync 回数, クラッシュ後に残る内容)
+        let cases = [
+            (DurabilityMode::Off, 0, 0, 1),
+            (DurabilityMode::Normal, 0, 1, 2),
+            (DurabilityMode::Full, 1, 2, 2),
+        ];
+        for (durability, on_write, on_flush, survived) in cases {
+            let injector = FaultInjector::new();
+            let options = DiskManagerOptions {
+                durability,
+                double_write_slots: 0,
+                ..Default::default()
+            };
+            let disk = DiskManager::with_options(injector.file(), options).unwrap();
+            let page_id = disk.allocate_page().unwrap();
+            disk.reset_stats();
+            disk.write_page_data(page_id, &mut filled_page(&disk, 1))
+                .unwrap();
+            assert_eq!(disk.stats().syncs, on_write);
+            // 明示的な sync() はどのモードでも永続化する
+            disk.sync().unwrap();
+            assert_eq!(disk.stats().syncs, on_write + 1);
+
+            let bufmgr =
+                BufferPoolManager::new(Box::new(disk), BufferPool::new(2, DEFAULT_PAGE_SIZE));
+            bufmgr.fetch_page_write(page_id).unwrap()[0] = 2;
+            bufmgr.reset_stats();
+            bufmgr.flush_all().unwrap();
+            assert_eq!(bufmgr.stats().disk.syncs, on_flush);
+            injector.crash();
+            drop(bufmgr);
+            assert_eq!(
+                read_first_byte(&open(&injector), page_id).unwrap(),
+                survived
+            );
+        }
+    }
+
+    #[test]
+    fn second_open_is_rejected_while_locked() {
+        let dir = TempDir::new("locked");
+        let path = dir.path("test.btr");
+        let read_only = DiskManagerOptions {
+            read_only: true,
+            ..Default::default()
+        };
+        let disk = DiskManager::open(&path).unwrap();
+        assert!(matches!(
+            DiskManager::open(&path),
+            Err(MyError::DatabaseLocked)
+        ));
+        assert!(matches!(
+            DiskManager::open_with_options(&path, read_only.clone()),
+            Err(MyError::DatabaseLocked)
+        ));
+        drop(disk);
+
+        // 読み出し専用なら同時に開けるが、その間は書き込み用には開けない
+        let first = DiskManager::open_with_options(&path, read_only.clone()).unwrap();
+        let second = DiskManager::open_with_options(&path, read_only).unwrap();
+        assert!(matches!(
+            DiskManager::open(&path),
+            Err(MyError::DatabaseLocked)
+        ));
+        drop((first, second));
+        DiskManager::open(&path).unwrap();
+    }
+
+    #[test]
+    fn read_only_database_rejects_writes() {
+        let dir = TempDir::new("read-only");
+        let path = dir.path("test.btr");
+        let disk = DiskManager::open(&path).unwrap();
+        let page_id = disk.allocate_page().unwrap();
+        disk.write_page_data(page_id, &mut filled_page(&disk, 4))
+            .unwrap();
+        drop(disk);
+
+        let options = DiskManagerOptions {
+            read_only: true,
+            ..Default::default()
+        };
+        let disk = DiskManager::open_with_options(&path, options).unwrap();
+        assert_eq!(read_first_byte(&disk, page_id).unwrap(), 4);
+        assert!(matches!(
+            disk.write_page_data(page_id, &mut filled_page(&disk, 5)),
+            Err(MyError::ReadOnly)
+        ));
+        assert!(matches!(disk.allocate_page(), Err(MyError::ReadOnly)));
+        assert!(matches!(
+            disk.deallocate_page(page_id),
+            Err(MyError::ReadOnly)
+        ));
+        assert!(matches!(
+            disk.set_catalog_root(0, page_id),
+            Err(MyError::ReadOnly)
+        ));
+        assert_eq!(read_first_byte(&disk, page_id).unwrap(), 4);
+    }
+
+    fn segment_count(path: &Path) -> usize {
+        (0..)
+            .take_while(|&i| segment_path(path, i).exists())
+            .count()
+    }
+
+    #[test]
+    fn pages_spread_over_segments_survive_reopen() {
+        let dir = TempDir::new("segments");
+        let path = dir.path("test.btr");
+        let options = DiskManagerOptions {
+            growth_pages: 1,
+            // 1 セグメントに 8 ページ
+            segment_size: MAX_PAGE_SIZE as u64,
+            double_write_slots: 0,
+            ..Default::default()
+        };
+        let disk = DiskManager::open_with_options(&path, options).unwrap();
+        let page_ids: Vec<PageId> = (0..20).map(|_| disk.allocate_page().unwrap()).collect();
+        for &page_id in &page_ids {
+            let mut data = filled_page(&disk, page_id.to_u64() as u8);
+            disk.write_page_data(page_id, &mut data).unwrap();
+        }
+        drop(disk);
+        let pages = page_ids.last().unwrap().to_u64() as usize + 1;
+        assert_eq!(segment_count(&path), pages.div_ceil(8));
+
+        // セグメントの大きさはスーパーブロックに記録されたものを使う
+        let disk = DiskManager::open(&path).unwrap();
+        for &page_id in &page_ids {
+            assert_eq!(
+                read_first_byte(&disk, page_id).unwrap(),
+                page_id.to_u64() as u8
+            );
+        }
+    }
+
+    #[test]
+    fn writes_across_a_segment_boundary_are_split() {
+        let dir = TempDir::new("segment-boundary");
+        let path = dir.path("test.btr");
+        let open_first = || {
+            OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(segment_path(&path, 0))
+                .unwrap()
+        };
+        let data: Vec<u8> = (0..4096).map(|i| i as u8).collect();
+        let file = SegmentedFile::open(&path, open_first(), 6000, false).unwrap();
+        file.write_all_at(&data, 4096).unwrap();
+        assert_eq!(file.segment_count(), 2);
+        assert_eq!(fs::metadata(segment_path(&path, 0)).unwrap().len(), 6000);
+        assert_eq!(file.size().unwrap(), 8192);
+        drop(file);
+
+        let file = SegmentedFile::open(&path, open_first(), 6000, false).unwrap();
+        let mut read = vec![0u8; 4096];
+        file.read_exact_at(&mut read, 4096).unwrap();
+        assert_eq!(read, data);
+        file.set_len(100).unwrap();
+        assert_eq!(segment_count(&path), 1);
+    }
+
+    #[test]
+    fn compact_removes_trailing_segments() {
+        let dir = TempDir::new("segment-truncate");
+        let path = dir.path("test.btr");
+        let options = DiskManagerOptions {
+            growth_pages: 1,
+            segment_size: MAX_PAGE_SIZE as u64,
+            double_write_slots: 0,
+            ..Default::default()
+        };
+        let disk = DiskManager::open_with_options(&path, options).unwrap();
+        let page_ids: Vec<PageId> = (0..30).map(|_| disk.allocate_page().unwrap()).collect();
+        for &page_id in &page_ids[2..] {
+            disk.deallocate_page(page_id).unwrap();
+        }
+        assert_eq!(segment_count(&path), 4);
+        disk.compact(&mut |_, _, _| false).unwrap();
+        let pages = page_ids[1].to_u64() as usize + 1;
+        assert_eq!(segment_count(&path), pages.div_ceil(8));
+        drop(disk);
+        let disk = DiskManager::open(&path).unwrap();
+        assert_eq!(disk.allocate_page().unwrap(), page_ids[2]);
+    }
+}
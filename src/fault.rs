@@ -0,0 +1,179 @@
+use std::io::{Error, ErrorKind};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use crate::disk::DiskFile;
+
+// 書き込みに仕込む障害
+#[derive(Debug, Clone, Copy)]
+enum WriteFault {
+    // 何も書かずにエラーを返す
+    Fail,
+    // 先頭の指定バイト数だけ書いてエラーを返す
+    Short(usize),
+    // 先頭の指定バイト数だけ書いて成功したふりをする
+    Torn(usize),
+}
+
+#[derive(Default)]
+struct FaultState {
+    // sync 済みで、クラッシュしても残る内容
+    durable: Vec<u8>,
+    // sync されていない書き込みも含む現在の内容
+    current: Vec<u8>,
+    // これまでに行われた書き込みの回数
+    writes: u64,
+    // 何回目の書き込みにどの障害を起こすか
+    write_faults: Vec<(u64, WriteFault)>,
+    // 読み出すたびに反転させるビット (ファイル先頭からのオフセット, ビット位置)
+    bit_flips: Vec<(u64, u8)>,
+    fail_sync: bool,
+}
+
+fn injected(kind: ErrorKind, what: &str) -> Error {
+    Error::new(kind, format!("injected fault: {}", what))
+}
+
+// 障害を注入するメモリ上のファイルを操作するハンドル
+// file() で作った FaultyFile を DiskManager::with_options に渡し、こちらから障害を仕込む
+#[derive(Clone, Default)]
+pub struct FaultInjector {
+    state: Arc<Mutex<FaultState>>,
+}
+
+impl FaultInjector {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // 同じ内容を共有するファイルを返す (クラッシュ後の再オープンにも使う)
+    pub fn file(&self) -> FaultyFile {
+        FaultyFile {
+            state: Arc::clone(&self.state),
+        }
+    }
+
+    // これから数えて n 回目 (1 始まり) の書き込みを失敗させる
+    pub fn fail_nth_write(&self, n: u64) {
+        self.add_write_fault(n, WriteFault::Fail);
+    }
+
+    // n 回目の書き込みを先頭 written バイトだけで打ち切り、エラーを返す
+    pub fn short_nth_write(&self, n: u64, written: usize) {
+        self.add_write_fault(n, WriteFault::Short(written));
+    }
+
+    // n 回目の書き込みを先頭 written バイトだけで打ち切り、成功を返す
+    pub fn tear_nth_write(&self, n: u64, written: usize) {
+        self.add_write_fault(n, WriteFault::Torn(written));
+    }
+
+    // offset にあるバイトの bit ビット目を、読み出すたびに反転させる
+    pub fn flip_bit_on_read(&self, offset: u64, bit: u8) {
+        assert!(bit < 8);
+        self.lock().bit_flips.push((offset, bit));
+    }
+
+    pub fn set_fail_sync(&self, fail: bool) {
+        self.lock().fail_sync = fail;
+    }
+
+    // 仕込んだ障害をすべて取り除く
+    pub fn clear_faults(&self) {
+        let mut state = self.lock();
+        state.write_faults.clear();
+        state.bit_flips.clear();
+        state.fail_sync = false;
+    }
+
+    // 電源断を模して、sync されていない書き込みを捨てる
+    pub fn crash(&self) {
+        let mut state = self.lock();
+        state.current = state.durable.clone();
+    }
+
+    pub fn write_count(&self) -> u64 {
+        self.lock().writes
+    }
+
+    fn add_write_fault(&self, n: u64, fault: WriteFault) {
+        assert!(n > 0, "writes are counted from 1");
+        let mut state = self.lock();
+        let nth = state.writes + n;
+        state.write_faults.push((nth, fault));
+    }
+
+    fn lock(&self) -> MutexGuard<'_, FaultState> {
+        self.state.lock().unwrap()
+    }
+}
+
+pub struct FaultyFile {
+    state: Arc<Mutex<FaultState>>,
+}
+
+impl DiskFile for FaultyFile {
+    fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> std::io::Result<()> {
+        let state = self.state.lock().unwrap();
+        let start = offset as usize;
+        let end = start + buf.len();
+        if end > state.current.len() {
+            return Err(Error::from(ErrorKind::UnexpectedEof));
+        }
+        buf.copy_from_slice(&state.current[start..end]);
+        for &(flip_offset, bit) in &state.bit_flips {
+            if (offset..end as u64).contains(&flip_offset) {
+                buf[(flip_offset - offset) as usize] ^= 1 << bit;
+            }
+        }
+        Ok(())
+    }
+
+    fn write_all_at(&self, buf: &[u8], offset: u64) -> std::io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        state.writes += 1;
+        let nth = state.writes;
+        let fault = state
+            .write_faults
+            .iter()
+            .position(|&(n, _)| n == nth)
+            .map(|i| state.write_faults.remove(i).1);
+        let (written, result) = match fault {
+            None => (buf.len(), Ok(())),
+            Some(WriteFault::Fail) => (0, Err(injected(ErrorKind::Other, "write failed"))),
+            Some(WriteFault::Short(n)) => (
+                n.min(buf.len()),
+                Err(injected(ErrorKind::WriteZero, "short write")),
+            ),
+            Some(WriteFault::Torn(n)) => (n.min(buf.len()), Ok(())),
+        };
+        let start = offset as usize;
+        let end = start + written;
+        if end > state.current.len() {
+            state.current.resize(end, 0);
+        }
+        state.current[start..end].copy_from_slice(&buf[..written]);
+        result
+    }
+
+    fn size(&self) -> std::io::Result<u64> {
+        Ok(self.state.lock().unwrap().current.len() as u64)
+    }
+
+    fn set_len(&self, size: u64) -> std::io::Result<()> {
+        self.state.lock().unwrap().current.resize(size as usize, 0);
+        Ok(())
+    }
+
+    fn sync_data(&self) -> std::io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if state.fail_sync {
+            return Err(injected(ErrorKind::Other, "sync failed"));
+        }
+        state.durable = state.current.clone();
+        Ok(())
+    }
+
+    fn sync_all(&self) -> std::io::Result<()> {
+        self.sync_data()
+    }
+}
@@ -0,0 +1,48 @@
+pub mod bgwriter;
+pub mod buffer;
+mod checksum;
+pub mod disk;
+#[cfg(any(test, feature = "fault-injection"))]
+pub mod fault;
+pub mod replacer;
+pub mod segment;
+pub mod stats;
+pub mod store;
+pub mod strategy;
+
+use thiserror::Error;
+
+use crate::disk::PageId;
+
+#[derive(Debug, Error)]
+pub enum MyError {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error("no free buffer available in buffer pool")]
+    NoFreeBuffer,
+    #[error(
+        "page {} is corrupted (expected checksum {expected:#010x}, actual {actual:#010x})",
+        .page_id.to_u64()
+    )]
+    PageCorrupted {
+        page_id: PageId,
+        expected: u32,
+        actual: u32,
+    },
+    #[error("not a database file")]
+    NotADatabase,
+    #[error("unsupported database format version {found} (supported: {supported})")]
+    UnsupportedFormatVersion { found: u32, supported: u32 },
+    #[error("unsupported page size {0}")]
+    UnsupportedPageSize(u32),
+    #[error("database file is locked by another process")]
+    DatabaseLocked,
+    #[error("database is opened read-only")]
+    ReadOnly,
+    #[error("page {} is pinned", .0.to_u64())]
+    PagePinned(PageId),
+    #[error("page {} is already free", .0.to_u64())]
+    PageAlreadyFree(PageId),
+    #[error("page {} is not pinned", .0.to_u64())]
+    PageNotPinned(PageId),
+}
@@ -0,0 +1,468 @@
+use std::collections::{HashMap, VecDeque};
+
+use crate::buffer::BufferId;
+use crate::disk::PageId;
+
+// 追い出すバッファを選ぶ方針
+pub trait Replacer: Send {
+    // バッファに置かれた page_id のページが参照されたことを記録する (読み込んだ直後も含む)
+    fn record_access(&mut self, buffer_id: BufferId, page_id: PageId);
+    // バッファがどのページも持たなくなったことを記録する
+    fn remove(&mut self, buffer_id: BufferId);
+    // 追い出すバッファを選ぶ。is_evictable が false のバッファ (ピン留め中) は選ばない
+    // 選んだバッファはこれまでの参照の記録を忘れ、空になったものとして扱う
+    fn victim(&mut self, is_evictable: &dyn Fn(BufferId) -> bool) -> Option<BufferId>;
+    // バッファの数が変わった。減った場合は、なくなったバッファは空になっている
+    fn resize(&mut self, pool_size: usize);
+}
+
+// BufferPool を作るときに選べる方針
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ReplacementPolicy {
+    #[default]
+    Clock,
+    Lru,
+    // K 回前の参照が最も古いページから追い出す
+    LruK(usize),
+    // 一度しか参照されていないページと、繰り返し参照されたページを別の列で管理する
+    TwoQueue,
+}
+
+impl ReplacementPolicy {
+    pub fn build(self, pool_size: usize) -> Box<dyn Replacer> {
+        match self {
+            ReplacementPolicy::Clock => Box::new(ClockReplacer::new(pool_size)),
+            ReplacementPolicy::Lru => Box::new(LruReplacer::new(pool_size)),
+            ReplacementPolicy::LruK(k) => Box::new(LruKReplacer::new(pool_size, k)),
+            ReplacementPolicy::TwoQueue => Box::new(TwoQueueReplacer::new(pool_size)),
+        }
+    }
+}
+
+// 参照されるたびに usage_count を増やし、時計の針が通るたびに減らす
+// usage_count が 0 になったバッファを追い出す
+pub struct ClockReplacer {
+    usage_counts: Vec<u64>,
+    next_victim_id: BufferId,
+}
+
+impl ClockReplacer {
+    pub fn new(pool_size: usize) -> Self {
+        Self {
+            usage_counts: vec![0; pool_size],
+            next_victim_id: BufferId::default(),
+        }
+    }
+
+    fn increment_id(&self, buffer_id: BufferId) -> BufferId {
+        BufferId((buffer_id.0 + 1) % self.usage_counts.len())
+    }
+}
+
+impl Replacer for ClockReplacer {
+    fn record_access(&mut self, buffer_id: BufferId, _page_id: PageId) {
+        self.usage_counts[buffer_id.0] += 1;
+    }
+
+    fn remove(&mut self, buffer_id: BufferId) {
+        self.usage_counts[buffer_id.0] = 0;
+    }
+
+    fn victim(&mut self, is_evictable: &dyn Fn(BufferId) -> bool) -> Option<BufferId> {
+        let pool_size = self.usage_counts.len();
+        let mut consecutive_pinned = 0;
+
+        loop {
+            let next_victim_id = self.next_victim_id;
+            if is_evictable(next_victim_id) {
+                let usage_count = &mut self.usage_counts[next_victim_id.0];
+                if *usage_count == 0 {
+                    return Some(next_victim_id);
+                }
+                *usage_count -= 1;
+                consecutive_pinned = 0;
+            } else {
+                consecutive_pinned += 1;
+                if consecutive_pinned >= pool_size {
+                    return None;
+                }
+            }
+
+            self.next_victim_id = self.increment_id(next_victim_id);
+        }
+    }
+
+    fn resize(&mut self, pool_size: usize) {
+        self.usage_counts.resize(pool_size, 0);
+        if self.next_victim_id.0 >= pool_size {
+            self.next_victim_id = BufferId::default();
+        }
+    }
+}
+
+// 最後に参照されたのが最も古いバッファを追い出す
+pub struct LruReplacer {
+    // 最後に参照された時刻 (空のバッファは None)
+    last_access: Vec<Option<u64>>,
+    clock: u64,
+}
+
+impl LruReplacer {
+    pub fn new(pool_size: usize) -> Self {
+        Self {
+            last_access: vec![None; pool_size],
+            clock: 0,
+        }
+    }
+}
+
+impl Replacer for LruReplacer {
+    fn record_access(&mut self, buffer_id: BufferId, _page_id: PageId) {
+        self.clock += 1;
+        self.last_access[buffer_id.0] = Some(self.clock);
+    }
+
+    fn remove(&mut self, buffer_id: BufferId) {
+        self.last_access[buffer_id.0] = None;
+    }
+
+    fn victim(&mut self, is_evictable: &dyn Fn(BufferId) -> bool) -> Option<BufferId> {
+        // None は Some より小さいので、空のバッファが先に選ばれる
+        let victim_id = (0..self.last_access.len())
+            .map(BufferId)
+            .filter(|&buffer_id| is_evictable(buffer_id))
+            .min_by_key(|buffer_id| self.last_access[buffer_id.0])?;
+        self.remove(victim_id);
+        Some(victim_id)
+    }
+
+    fn resize(&mut self, pool_size: usize) {
+        self.last_access.resize(pool_size, None);
+    }
+}
+
+// 最近 K 回の参照履歴を持ち、K 回前の参照が最も古いページを追い出す
+// 参照が K 回に満たないページは K 回前の参照を無限の過去とみなし、最初の参照が古いものから追い出す
+// 追い出したページの履歴もプールの大きさの分だけ残しておき、読み込み直したときに引き継ぐ
+pub struct LruKReplacer {
+    k: usize,
+    // 各バッファに置かれているページ
+    frames: Vec<Option<PageId>>,
+    histories: HashMap<PageId, VecDeque<u64>>,
+    // 履歴だけが残っている、追い出したページ (古い順)
+    evicted: VecDeque<PageId>,
+    clock: u64,
+}
+
+impl LruKReplacer {
+    pub fn new(pool_size: usize, k: usize) -> Self {
+        assert!(k > 0, "K of LRU-K must be at least 1");
+        Self {
+            k,
+            frames: vec![None; pool_size],
+            histories: HashMap::new(),
+            evicted: VecDeque::new(),
+            clock: 0,
+        }
+    }
+
+    fn forget_evicted(&mut self) {
+        while self.evicted.len() > self.frames.len() {
+            let page_id = self.evicted.pop_front().unwrap();
+            if !self.frames.contains(&Some(page_id)) {
+                self.histories.remove(&page_id);
+            }
+        }
+    }
+}
+
+impl Replacer for LruKReplacer {
+    fn record_access(&mut self, buffer_id: BufferId, page_id: PageId) {
+        self.clock += 1;
+        self.frames[buffer_id.0] = Some(page_id);
+        self.evicted
+            .retain(|&evicted_page_id| evicted_page_id != page_id);
+        let history = self.histories.entry(page_id).or_default();
+        if history.len() == self.k {
+            history.pop_front();
+        }
+        history.push_back(self.clock);
+    }
+
+    fn remove(&mut self, buffer_id: BufferId) {
+        if let Some(page_id) = self.frames[buffer_id.0].take() {
+            self.histories.remove(&page_id);
+        }
+    }
+
+    fn victim(&mut self, is_evictable: &dyn Fn(BufferId) -> bool) -> Option<BufferId> {
+        // 空のバッファ、参照が K 回に満たないページ、K 回前の参照が古いページの順に選ぶ
+        let victim_id = (0..self.frames.len())
+            .map(BufferId)
+            .filter(|&buffer_id| is_evictable(buffer_id))
+            .min_by_key(|buffer_id| {
+                let history =
+                    self.frames[buffer_id.0].and_then(|page_id| self.histories.get(&page_id));
+                match history {
+                    None => (0, 0),
+                    Some(history) if history.len() < self.k => (1, history[0]),
+                    Some(history) => (2, history[0]),
+                }
+            })?;
+        if let Some(page_id) = self.frames[victim_id.0].take() {
+            self.evicted.push_back(page_id);
+            self.forget_evicted();
+        }
+        Some(victim_id)
+    }
+
+    fn resize(&mut self, pool_size: usize) {
+        for buffer_id in pool_size..self.frames.len() {
+            self.remove(BufferId(buffer_id));
+        }
+        self.frames.resize(pool_size, None);
+        self.forget_evicted();
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum TwoQueueEntry {
+    Empty,
+    // 初めて読み込まれたページ (読み込んだ時刻)
+    A1in(u64, PageId),
+    // 追い出された後にもう一度参照されたページ (最後に参照された時刻)
+    Am(u64),
+}
+
+// 2Q: 初めて読み込まれたページは FIFO の A1in に入り、その間の参照は数えない
+// A1in から追い出したページは A1out に覚えておき、そこにある間にもう一度読み込まれたら LRU の Am に入れる
+// 一度しか読まれないページは A1in から追い出されるので、Am のページを押し出さない
+pub struct TwoQueueReplacer {
+    entries: Vec<TwoQueueEntry>,
+    // A1in の上限 (プールの 1/4)
+    a1in_max: usize,
+    // A1in から追い出したページ (古い順)。上限はプールの 1/2
+    a1out: VecDeque<PageId>,
+    a1out_max: usize,
+    clock: u64,
+}
+
+impl TwoQueueReplacer {
+    pub fn new(pool_size: usize) -> Self {
+        Self {
+            entries: vec![TwoQueueEntry::Empty; pool_size],
+            a1in_max: (pool_size / 4).max(1),
+            a1out: VecDeque::new(),
+            a1out_max: (pool_size / 2).max(1),
+            clock: 0,
+        }
+    }
+
+    // 条件に合う追い出せるバッファのうち、時刻が最も古いものを返す
+    fn oldest(
+        &self,
+        is_evictable: &dyn Fn(BufferId) -> bool,
+        time: impl Fn(TwoQueueEntry) -> Option<u64>,
+    ) -> Option<BufferId> {
+        (0..self.entries.len())
+            .map(BufferId)
+            .filter(|&buffer_id| is_evictable(buffer_id))
+            .filter_map(|buffer_id| Some((time(self.entries[buffer_id.0])?, buffer_id)))
+            .min_by_key(|&(time, _)| time)
+            .map(|(_, buffer_id)| buffer_id)
+    }
+}
+
+impl Replacer for TwoQueueReplacer {
+    fn record_access(&mut self, buffer_id: BufferId, page_id: PageId) {
+        self.clock += 1;
+        let entry = &mut self.entries[buffer_id.0];
+        *entry = match *entry {
+            TwoQueueEntry::Empty => {
+                if let Some(index) = self.a1out.iter().position(|&id| id == page_id) {
+                    self.a1out.remove(index);
+                    TwoQueueEntry::Am(self.clock)
+                } else {
+                    TwoQueueEntry::A1in(self.clock, page_id)
+                }
+            }
+            TwoQueueEntry::A1in(time, _) => TwoQueueEntry::A1in(time, page_id),
+            TwoQueueEntry::Am(_) => TwoQueueEntry::Am(self.clock),
+        };
+    }
+
+    fn remove(&mut self, buffer_id: BufferId) {
+        self.entries[buffer_id.0] = TwoQueueEntry::Empty;
+    }
+
+    fn victim(&mut self, is_evictable: &dyn Fn(BufferId) -> bool) -> Option<BufferId> {
+        let empty = self.oldest(is_evictable, |entry| match entry {
+            TwoQueueEntry::Empty => Some(0),
+            _ => None,
+        });
+        let a1in = || {
+            self.oldest(is_evictable, |entry| match entry {
+                TwoQueueEntry::A1in(time, _) => Some(time),
+                _ => None,
+            })
+        };
+        let am = || {
+            self.oldest(is_evictable, |entry| match entry {
+                TwoQueueEntry::Am(time) => Some(time),
+                _ => None,
+            })
+        };
+        let a1in_len = self
+            .entries
+            .iter()
+            .filter(|entry| matches!(entry, TwoQueueEntry::A1in(..)))
+            .count();
+        let victim_id = if a1in_len > self.a1in_max {
+            empty.or_else(a1in).or_else(am)
+        } else {
+            empty.or_else(am).or_else(a1in)
+        }?;
+        if let TwoQueueEntry::A1in(_, page_id) = self.entries[victim_id.0] {
+            self.a1out.push_back(page_id);
+            if self.a1out.len() > self.a1out_max {
+                self.a1out.pop_front();
+            }
+        }
+        self.remove(victim_id);
+        Some(victim_id)
+    }
+
+    fn resize(&mut self, pool_size: usize) {
+        self.entries.resize(pool_size, TwoQueueEntry::Empty);
+        self.a1in_max = (pool_size / 4).max(1);
+        self.a1out_max = (pool_size / 2).max(1);
+        while self.a1out.len() > self.a1out_max {
+            self.a1out.pop_front();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // バッファプールの代わりに、ページをバッファに置く操作だけを真似る
+    struct Simulation {
+        replacer: Box<dyn Replacer>,
+        frames: Vec<Option<PageId>>,
+    }
+
+    impl Simulation {
+        fn new(policy: ReplacementPolicy, pool_size: usize) -> Self {
+            Self {
+                replacer: policy.build(pool_size),
+                frames: vec![None; pool_size],
+            }
+        }
+
+        // ページを参照し、追い出したページがあれば返す
+        fn access(&mut self, page_id: u64) -> Option<u64> {
+            let page_id = PageId(page_id);
+            if let Some(buffer_id) = self.frames.iter().position(|&id| id == Some(page_id)) {
+                self.replacer.record_access(BufferId(buffer_id), page_id);
+                return None;
+            }
+            let buffer_id = match self.frames.iter().position(Option::is_none) {
+                Some(buffer_id) => BufferId(buffer_id),
+                None => self.replacer.victim(&|_| true).unwrap(),
+            };
+            let evicted = self.frames[buffer_id.0].replace(page_id);
+            self.replacer.record_access(buffer_id, page_id);
+            evicted.map(|page_id| page_id.0)
+        }
+
+        fn is_resident(&self, page_id: u64) -> bool {
+            self.frames.contains(&Some(PageId(page_id)))
+        }
+    }
+
+    const POLICIES: [ReplacementPolicy; 4] = [
+        ReplacementPolicy::Clock,
+        ReplacementPolicy::Lru,
+        ReplacementPolicy::LruK(2),
+        ReplacementPolicy::TwoQueue,
+    ];
+
+    #[test]
+    fn pinned_buffers_are_never_chosen() {
+        for policy in POLICIES {
+            let mut sim = Simulation::new(policy, 3);
+            for page_id in 0..3 {
+                sim.access(page_id);
+            }
+            assert_eq!(sim.replacer.victim(&|_| false), None, "{:?}", policy);
+            let victim = sim.replacer.victim(&|buffer_id| buffer_id == BufferId(1));
+            assert_eq!(victim, Some(BufferId(1)), "{:?}", policy);
+        }
+    }
+
+    #[test]
+    fn clock_gives_referenced_buffers_a_second_chance() {
+        let mut sim = Simulation::new(ReplacementPolicy::Clock, 3);
+        for page_id in [1, 2, 3, 1] {
+            sim.access(page_id);
+        }
+        assert_eq!(sim.access(4), Some(2));
+        assert_eq!(sim.access(5), Some(3));
+        assert!(sim.is_resident(1));
+    }
+
+    #[test]
+    fn lru_evicts_the_least_recently_used_page() {
+        let mut sim = Simulation::new(ReplacementPolicy::Lru, 3);
+        for page_id in [1, 2, 3, 1] {
+            sim.access(page_id);
+        }
+        assert_eq!(sim.access(4), Some(2));
+        assert_eq!(sim.access(5), Some(3));
+        assert_eq!(sim.access(6), Some(1));
+    }
+
+    #[test]
+    fn lru_k_evicts_by_the_kth_most_recent_access() {
+        let mut sim = Simulation::new(ReplacementPolicy::LruK(2), 3);
+        // 3 は最後に参照されたが 1 回しか参照されていないので先に追い出す
+        for page_id in [1, 2, 2, 1, 3] {
+            sim.access(page_id);
+        }
+        assert_eq!(sim.access(4), Some(3));
+        sim.access(4);
+        // 1 は 2 より最近参照されたが、2 回前の参照は 1 の方が古い
+        assert_eq!(sim.access(5), Some(1));
+    }
+
+    #[test]
+    fn lru_k_keeps_the_history_of_evicted_pages() {
+        let mut sim = Simulation::new(ReplacementPolicy::LruK(2), 2);
+        sim.access(1);
+        sim.access(2);
+        assert_eq!(sim.access(11), Some(1));
+        // 追い出した 1 を読み込み直すと、以前の参照と合わせて 2 回になる
+        assert_eq!(sim.access(1), Some(2));
+        assert_eq!(sim.access(3), Some(11));
+        assert_eq!(sim.access(4), Some(3));
+        assert!(sim.is_resident(1));
+    }
+
+    #[test]
+    fn two_queue_keeps_hot_pages_during_a_scan() {
+        let scan = |policy| {
+            let mut sim = Simulation::new(policy, 8);
+            // 0 と 1 を一度追い出されてから参照し直された、よく使うページにする
+            for page_id in [0, 1, 100, 101, 102, 103, 104, 105, 106, 0, 1] {
+                sim.access(page_id);
+            }
+            for page_id in 200..300 {
+                sim.access(page_id);
+            }
+            sim.is_resident(0) && sim.is_resident(1)
+        };
+        assert!(scan(ReplacementPolicy::TwoQueue));
+        assert!(!scan(ReplacementPolicy::Lru));
+    }
+}
@@ -0,0 +1,161 @@
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+
+use crate::disk::DiskFile;
+
+// セグメントファイルの大きさの既定値 (1 GiB)
+pub const DEFAULT_SEGMENT_SIZE: u64 = 1 << 30;
+
+// `test.btr` に対して `test.btr.0`, `test.btr.1`, ... というパスを返す
+pub fn segment_path(base_path: &Path, index: usize) -> PathBuf {
+    let mut path = OsString::from(base_path.as_os_str());
+    path.push(format!(".{}", index));
+    PathBuf::from(path)
+}
+
+// 固定サイズのセグメントファイルを連結して、一つの大きなファイルに見せる
+// オフセット offset は (offset / segment_size) 番目のセグメントの (offset % segment_size) に対応する
+pub struct SegmentedFile {
+    base_path: PathBuf,
+    segment_size: u64,
+    read_only: bool,
+    segments: RwLock<Vec<File>>,
+}
+
+impl SegmentedFile {
+    // 先頭のセグメントは呼び出し側が開いて (ロックを取って) 渡す
+    pub fn open(
+        base_path: impl AsRef<Path>,
+        first_segment: File,
+        segment_size: u64,
+        read_only: bool,
+    ) -> std::io::Result<Self> {
+        if segment_size == 0 {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "segment size must not be zero",
+            ));
+        }
+        let base_path = base_path.as_ref().to_path_buf();
+        let mut segments = vec![first_segment];
+        // 続きのセグメントを見つからなくなるまで開く
+        loop {
+            let path = segment_path(&base_path, segments.len());
+            match OpenOptions::new().read(true).write(!read_only).open(&path) {
+                Ok(file) => segments.push(file),
+                Err(e) if e.kind() == ErrorKind::NotFound => break,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(Self {
+            base_path,
+            segment_size,
+            read_only,
+            segments: RwLock::new(segments),
+        })
+    }
+
+    pub fn segment_count(&self) -> usize {
+        self.segments.read().unwrap().len()
+    }
+
+    // 必要な数までセグメントを増やす (途中のセグメントは満杯の大きさにしておく)
+    fn ensure_segments(&self, count: usize) -> std::io::Result<()> {
+        if self.segment_count() >= count {
+            return Ok(());
+        }
+        let mut segments = self.segments.write().unwrap();
+        while segments.len() < count {
+            segments.last().unwrap().set_len(self.segment_size)?;
+            let file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(segment_path(&self.base_path, segments.len()))?;
+            segments.push(file);
+        }
+        Ok(())
+    }
+
+    // offset から len バイトを、セグメントの境界で分割して順に処理する
+    fn for_each_chunk(
+        &self,
+        offset: u64,
+        len: usize,
+        mut f: impl FnMut(&File, u64, std::ops::Range<usize>) -> std::io::Result<()>,
+    ) -> std::io::Result<()> {
+        let segments = self.segments.read().unwrap();
+        let mut done = 0;
+        while done < len {
+            let pos = offset + done as u64;
+            let index = (pos / self.segment_size) as usize;
+            let within = pos % self.segment_size;
+            let chunk = ((self.segment_size - within) as usize).min(len - done);
+            let segment = segments
+                .get(index)
+                .ok_or_else(|| Error::from(ErrorKind::UnexpectedEof))?;
+            f(segment, within, done..done + chunk)?;
+            done += chunk;
+        }
+        Ok(())
+    }
+}
+
+impl DiskFile for SegmentedFile {
+    fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> std::io::Result<()> {
+        self.for_each_chunk(offset, buf.len(), |segment, within, range| {
+            segment.read_exact_at(&mut buf[range], within)
+        })
+    }
+
+    fn write_all_at(&self, buf: &[u8], offset: u64) -> std::io::Result<()> {
+        if !buf.is_empty() {
+            let last = offset + buf.len() as u64 - 1;
+            self.ensure_segments((last / self.segment_size) as usize + 1)?;
+        }
+        self.for_each_chunk(offset, buf.len(), |segment, within, range| {
+            segment.write_all_at(&buf[range], within)
+        })
+    }
+
+    fn size(&self) -> std::io::Result<u64> {
+        let segments = self.segments.read().unwrap();
+        let full = (segments.len() - 1) as u64 * self.segment_size;
+        Ok(full + segments.last().unwrap().metadata()?.len())
+    }
+
+    fn set_len(&self, size: u64) -> std::io::Result<()> {
+        if self.read_only {
+            return Err(Error::from(ErrorKind::PermissionDenied));
+        }
+        let count = (size.div_ceil(self.segment_size) as usize).max(1);
+        self.ensure_segments(count)?;
+        let mut segments = self.segments.write().unwrap();
+        // 縮める場合は不要になった後ろのセグメントを削除する
+        while segments.len() > count {
+            let index = segments.len() - 1;
+            segments.pop();
+            fs::remove_file(segment_path(&self.base_path, index))?;
+        }
+        let last_len = size - (count - 1) as u64 * self.segment_size;
+        segments.last().unwrap().set_len(last_len)
+    }
+
+    fn sync_data(&self) -> std::io::Result<()> {
+        for segment in self.segments.read().unwrap().iter() {
+            segment.sync_data()?;
+        }
+        Ok(())
+    }
+
+    fn sync_all(&self) -> std::io::Result<()> {
+        for segment in self.segments.read().unwrap().iter() {
+            segment.sync_all()?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,120 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+// 統計用のカウンタ (複数スレッドから加算できる)
+#[derive(Debug, Default)]
+pub(crate) struct Counter(AtomicU64);
+
+impl Counter {
+    pub(crate) fn add(&self, n: u64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub(crate) fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn reset(&self) {
+        self.0.store(0, Ordering::Relaxed);
+    }
+}
+
+// ページの格納先で行われた I/O の統計
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DiskStats {
+    pub pages_read: u64,
+    pub pages_written: u64,
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+    pub syncs: u64,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct DiskCounters {
+    pages_read: Counter,
+    pages_written: Counter,
+    bytes_read: Counter,
+    bytes_written: Counter,
+    syncs: Counter,
+}
+
+impl DiskCounters {
+    pub(crate) fn record_read(&self, bytes: usize) {
+        self.pages_read.add(1);
+        self.bytes_read.add(bytes as u64);
+    }
+
+    pub(crate) fn record_write(&self, bytes: usize) {
+        self.pages_written.add(1);
+        self.bytes_written.add(bytes as u64);
+    }
+
+    pub(crate) fn record_sync(&self) {
+        self.syncs.add(1);
+    }
+
+    pub(crate) fn snapshot(&self) -> DiskStats {
+        DiskStats {
+            pages_read: self.pages_read.get(),
+            pages_written: self.pages_written.get(),
+            bytes_read: self.bytes_read.get(),
+            bytes_written: self.bytes_written.get(),
+            syncs: self.syncs.get(),
+        }
+    }
+
+    pub(crate) fn reset(&self) {
+        self.pages_read.reset();
+        self.pages_written.reset();
+        self.bytes_read.reset();
+        self.bytes_written.reset();
+        self.syncs.reset();
+    }
+}
+
+// バッファプールの統計 (ページの格納先の統計も含む)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BufferPoolStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    // 追い出すときに変更されていたため書き戻したページ数
+    pub dirty_writebacks: u64,
+    // 先読みで読み込んだページ数
+    pub prefetched_pages: u64,
+    // バックグラウンドライタが書き込んだページ数
+    pub background_writes: u64,
+    pub disk: DiskStats,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct BufferPoolCounters {
+    pub(crate) hits: Counter,
+    pub(crate) misses: Counter,
+    pub(crate) evictions: Counter,
+    pub(crate) dirty_writebacks: Counter,
+    pub(crate) prefetched_pages: Counter,
+    pub(crate) background_writes: Counter,
+}
+
+impl BufferPoolCounters {
+    pub(crate) fn snapshot(&self, disk: DiskStats) -> BufferPoolStats {
+        BufferPoolStats {
+            hits: self.hits.get(),
+            misses: self.misses.get(),
+            evictions: self.evictions.get(),
+            dirty_writebacks: self.dirty_writebacks.get(),
+            prefetched_pages: self.prefetched_pages.get(),
+            background_writes: self.background_writes.get(),
+            disk,
+        }
+    }
+
+    pub(crate) fn reset(&self) {
+        self.hits.reset();
+        self.misses.reset();
+        self.evictions.reset();
+        self.dirty_writebacks.reset();
+        self.prefetched_pages.reset();
+        self.background_writes.reset();
+    }
+}
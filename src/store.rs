@@ -0,0 +1,324 @@
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind};
+use std::path::Path;
+use std::sync::Mutex;
+
+use crate::disk::{usable_page_size, DiskManager, DiskManagerOptions, DurabilityMode, PageId};
+use crate::stats::{DiskCounters, DiskStats};
+use crate::MyError;
+
+// この名前で開くとファイルを使わずメモリ上だけにデータベースを作る
+pub const MEMORY_PATH: &str = ":memory:";
+
+// compact でページを移動したときの、旧ページIDから新ページIDへの対応
+pub type Relocations = HashMap<PageId, PageId>;
+
+// compact でページを移動した後、生きているページごとに呼ばれて中の参照を書き換える
+// (ページID, ページの内容, 移動の対応) を受け取り、内容を書き換えたら true を返す
+pub type ReferenceRewriter<'a> = dyn FnMut(PageId, &mut [u8], &Relocations) -> bool + 'a;
+
+// ページを後ろから前の空きへ詰める移動の計画を立てる
+// 生きているページ (昇順) と空きページ (昇順) から、移動の一覧と詰めた後の採番位置を返す
+pub(crate) fn plan_compaction(
+    live_pages: &[PageId],
+    free_pages: &[PageId],
+    first_page_id: PageId,
+) -> (Vec<(PageId, PageId)>, u64) {
+    let mut moves = vec![];
+    let mut holes = free_pages.iter();
+    let mut remaining = live_pages.len();
+    for &page_id in live_pages.iter().rev() {
+        match holes.next() {
+            Some(&hole) if hole < page_id => {
+                moves.push((page_id, hole));
+                remaining -= 1;
+            }
+            _ => break,
+        }
+    }
+    let mut next_page_id = first_page_id.to_u64();
+    for &page_id in &live_pages[..remaining] {
+        next_page_id = next_page_id.max(page_id.to_u64() + 1);
+    }
+    for &(_, hole) in &moves {
+        next_page_id = next_page_id.max(hole.to_u64() + 1);
+    }
+    (moves, next_page_id)
+}
+
+// バッファプールから見たページの格納先
+pub trait PageStore: Send + Sync {
+    fn page_size(&self) -> usize;
+    // ページのうち、末尾のチェックサムを除いて使える大きさ
+    fn usable_page_size(&self) -> usize {
+        usable_page_size(self.page_size())
+    }
+    fn read_page_data(&self, page_id: PageId, data: &mut [u8]) -> Result<(), MyError>;
+    // 連続するページをまとめて読み、読み出したページ数を返す
+    // 割り当てられていないページや解放済みのページに当たったら、その手前までにする
+    fn read_pages(&self, first_page_id: PageId, pages: &mut [&mut [u8]]) -> Result<usize, MyError>;
+    fn write_page_data(&self, page_id: PageId, data: &mut [u8]) -> Result<(), MyError>;
+    fn write_pages(&self, pages: &mut [(PageId, &mut [u8])]) -> Result<(), MyError> {
+        for (page_id, data) in pages.iter_mut() {
+            self.write_page_data(*page_id, data)?;
+        }
+        Ok(())
+    }
+    fn allocate_page(&self) -> Result<PageId, MyError>;
+    fn deallocate_page(&self, page_id: PageId) -> Result<(), MyError>;
+    fn sync(&self) -> Result<(), MyError>;
+    fn durability(&self) -> DurabilityMode;
+    // 生きているページを前の空きページへ移動し、末尾の空きを切り詰める
+    fn compact(&self, rewriter: &mut ReferenceRewriter) -> Result<Relocations, MyError>;
+    fn stats(&self) -> DiskStats;
+    fn reset_stats(&self);
+}
+
+pub fn open_page_store(
+    path: impl AsRef<Path>,
+    options: DiskManagerOptions,
+) -> Result<Box<dyn PageStore>, MyError> {
+    if path.as_ref() == Path::new(MEMORY_PATH) {
+        return Ok(Box::new(MemoryPageStore::new(options.page_size)?));
+    }
+    Ok(Box::new(DiskManager::open_with_options(path, options)?))
+}
+
+struct MemoryState {
+    pages: Vec<Box<[u8]>>,
+    free_pages: Vec<PageId>,
+}
+
+// プロセスが終わると消える、メモリ上だけのページの格納先
+pub struct MemoryPageStore {
+    page_size: usize,
+    state: Mutex<MemoryState>,
+    counters: DiskCounters,
+}
+
+impl MemoryPageStore {
+    pub fn new(page_size: usize) -> Result<Self, MyError> {
+        crate::disk::check_page_size(page_size)?;
+        // ファイルと揃えるため、ページ 0 は使わずに空けておく
+        let pages = vec![vec![0u8; page_size].into_boxed_slice()];
+        Ok(Self {
+            page_size,
+            state: Mutex::new(MemoryState {
+                pages,
+                free_pages: vec![],
+            }),
+            counters: DiskCounters::default(),
+        })
+    }
+
+    fn check_page_id(&self, state: &MemoryState, page_id: PageId) -> std::io::Result<usize> {
+        let index = page_id.to_u64() as usize;
+        if page_id.to_u64() == 0 || index >= state.pages.len() {
+            return Err(Error::new(
+                ErrorKind::UnexpectedEof,
+                format!("page {} is not allocated", page_id.to_u64()),
+            ));
+        }
+        Ok(index)
+    }
+}
+
+impl PageStore for MemoryPageStore {
+    fn page_size(&self) -> usize {
+        self.page_size
+    }
+
+    fn read_page_data(&self, page_id: PageId, data: &mut [u8]) -> Result<(), MyError> {
+        let state = self.state.lock().unwrap();
+        let index = self.check_page_id(&state, page_id)?;
+        data.copy_from_slice(&state.pages[index]);
+        self.counters.record_read(data.len());
+        Ok(())
+    }
+
+    fn read_pages(&self, first_page_id: PageId, pages: &mut [&mut [u8]]) -> Result<usize, MyError> {
+        let state = self.state.lock().unwrap();
+        let first = first_page_id.to_u64() as usize;
+        let count = state.pages.len().saturating_sub(first).min(pages.len());
+        for (i, data) in pages[..count].iter_mut().enumerate() {
+            let page_id = PageId((first + i) as u64);
+            if state.free_pages.contains(&page_id) {
+                return Ok(i);
+            }
+            let index = self.check_page_id(&state, page_id)?;
+            data.copy_from_slice(&state.pages[index]);
+            self.counters.record_read(data.len());
+        }
+        Ok(count)
+    }
+
+    fn write_page_data(&self, page_id: PageId, data: &mut [u8]) -> Result<(), MyError> {
+        let mut state = self.state.lock().unwrap();
+        let index = self.check_page_id(&state, page_id)?;
+        state.pages[index].copy_from_slice(data);
+        self.counters.record_write(data.len());
+        Ok(())
+    }
+
+    fn allocate_page(&self) -> Result<PageId, MyError> {
+        let mut state = self.state.lock().unwrap();
+        if let Some(page_id) = state.free_pages.pop() {
+            return Ok(page_id);
+        }
+        let page_id = PageId(state.pages.len() as u64);
+        state
+            .pages
+            .push(vec![0u8; self.page_size].into_boxed_slice());
+        Ok(page_id)
+    }
+
+    fn deallocate_page(&self, page_id: PageId) -> Result<(), MyError> {
+        let mut state = self.state.lock().unwrap();
+        let index = self.check_page_id(&state, page_id)?;
+        if state.free_pages.contains(&page_id) {
+            return Err(MyError::PageAlreadyFree(page_id));
+        }
+        state.pages[index].fill(0);
+        state.free_pages.push(page_id);
+        Ok(())
+    }
+
+    fn sync(&self) -> Result<(), MyError> {
+        self.counters.record_sync();
+        Ok(())
+    }
+
+    fn durability(&self) -> DurabilityMode {
+        DurabilityMode::Off
+    }
+
+    fn compact(&self, rewriter: &mut ReferenceRewriter) -> Result<Relocations, MyError> {
+        let mut state = self.state.lock().unwrap();
+        let mut free_pages = std::mem::take(&mut state.free_pages);
+        free_pages.sort();
+        let live_pages: Vec<PageId> = (1..state.pages.len() as u64)
+            .map(PageId)
+            .filter(|page_id| free_pages.binary_search(page_id).is_err())
+            .collect();
+        let (moves, next_page_id) = plan_compaction(&live_pages, &free_pages, PageId(1));
+        for &(from, to) in &moves {
+            let page = std::mem::replace(
+                &mut state.pages[from.to_u64() as usize],
+                vec![0u8; self.page_size].into_boxed_slice(),
+            );
+            state.pages[to.to_u64() as usize] = page;
+        }
+        state.pages.truncate(next_page_id as usize);
+        let relocations: Relocations = moves.into_iter().collect();
+        if !relocations.is_empty() {
+            for (index, page) in state.pages.iter_mut().enumerate().skip(1) {
+                rewriter(PageId(index as u64), page, &relocations);
+            }
+        }
+        Ok(relocations)
+    }
+
+    fn stats(&self) -> DiskStats {
+        self.counters.snapshot()
+    }
+
+    fn reset_stats(&self) {
+        self.counters.reset();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn page_ids(ids: &[u64]) -> Vec<PageId> {
+        ids.iter().copied().map(PageId).collect()
+    }
+
+    #[test]
+    fn plan_compaction_fills_holes_from_the_back() {
+        let (moves, next_page_id) =
+            plan_compaction(&page_ids(&[1, 3, 5, 6]), &page_ids(&[2, 4]), PageId(1));
+        assert_eq!(moves, vec![(PageId(6), PageId(2)), (PageId(5), PageId(4))]);
+        assert_eq!(next_page_id, 5);
+    }
+
+    #[test]
+    fn plan_compaction_without_holes_before_live_pages() {
+        // 空きページが末尾にしかなければ移動せず、その手前で切り詰める
+        let (moves, next_page_id) =
+            plan_compaction(&page_ids(&[1, 2, 3]), &page_ids(&[4, 5]), PageId(1));
+        assert!(moves.is_empty());
+        assert_eq!(next_page_id, 4);
+        let (moves, next_page_id) = plan_compaction(&page_ids(&[1, 2]), &[], PageId(1));
+        assert!(moves.is_empty());
+        assert_eq!(next_page_id, 3);
+        // 生きているページがなければ先頭まで切り詰める
+        let (moves, next_page_id) = plan_compaction(&[], &page_ids(&[3, 4]), PageId(3));
+        assert!(moves.is_empty());
+        assert_eq!(next_page_id, 3);
+    }
+
+    #[test]
+    fn plan_compaction_stops_when_holes_run_out() {
+        let (moves, next_page_id) =
+            plan_compaction(&page_ids(&[1, 3, 4, 5, 6]), &page_ids(&[2]), PageId(1));
+        assert_eq!(moves, vec![(PageId(6), PageId(2))]);
+        assert_eq!(next_page_id, 6);
+    }
+
+    #[test]
+    fn memory_store_compact_moves_pages_and_rewrites_references() {
+        let store = MemoryPageStore::new(4096).unwrap();
+        let ids: Vec<PageId> = (0..6).map(|_| store.allocate_page().unwrap()).collect();
+        for &page_id in &ids {
+            let mut data = vec![page_id.to_u64() as u8; 4096];
+            // 先頭のページは最後のページを参照する
+            data[8..16].copy_from_slice(&ids[5].to_u64().to_le_bytes());
+            store.write_page_data(page_id, &mut data).unwrap();
+        }
+        store.deallocate_page(ids[1]).unwrap();
+        store.deallocate_page(ids[3]).unwrap();
+
+        let mut rewritten = vec![];
+        let relocations = store
+            .compact(&mut |page_id, data, relocations| {
+                rewritten.push(page_id);
+                let target = PageId(u64::from_le_bytes(data[8..16].try_into().unwrap()));
+                match relocations.get(&target) {
+                    Some(new_page_id) => {
+                        data[8..16].copy_from_slice(&new_page_id.to_u64().to_le_bytes());
+                        true
+                    }
+                    None => false,
+                }
+            })
+            .unwrap();
+        assert_eq!(relocations.len(), 2);
+        assert_eq!(relocations[&ids[5]], ids[1]);
+        assert_eq!(relocations[&ids[4]], ids[3]);
+        assert_eq!(rewritten, page_ids(&[1, 2, 3, 4]));
+
+        let mut data = vec![0u8; 4096];
+        store.read_page_data(ids[1], &mut data).unwrap();
+        assert_eq!(data[0], ids[5].to_u64() as u8);
+        store.read_page_data(ids[0], &mut data).unwrap();
+        assert_eq!(&data[8..16], &ids[1].to_u64().to_le_bytes());
+        // 末尾は切り詰められている
+        assert!(store.read_page_data(ids[4], &mut data).is_err());
+        assert_eq!(store.allocate_page().unwrap(), ids[4]);
+    }
+
+    #[test]
+    fn memory_store_rejects_double_free() {
+        let store = MemoryPageStore::new(4096).unwrap();
+        let page_ids: Vec<PageId> = (0..2).map(|_| store.allocate_page().unwrap()).collect();
+        store.deallocate_page(page_ids[1]).unwrap();
+        assert!(matches!(
+            store.deallocate_page(page_ids[1]),
+            Err(MyError::PageAlreadyFree(_))
+        ));
+        assert_eq!(store.allocate_page().unwrap(), page_ids[1]);
+        assert_eq!(store.allocate_page().unwrap(), PageId(3));
+    }
+}
@@ -0,0 +1,56 @@
+use crate::buffer::BufferId;
+use crate::disk::PageId;
+
+// 大量のページを一度ずつ読み書きする処理の種類ごとの、リングのページ数
+pub const BULK_READ_RING_PAGES: usize = 32;
+pub const BULK_WRITE_RING_PAGES: usize = 256;
+pub const VACUUM_RING_PAGES: usize = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessStrategyKind {
+    // テーブル全体の走査
+    BulkRead,
+    // 大量のページの作成 (一括ロード)
+    BulkWrite,
+    // 不要になったページの回収
+    Vacuum,
+}
+
+impl AccessStrategyKind {
+    fn ring_pages(self) -> usize {
+        match self {
+            AccessStrategyKind::BulkRead => BULK_READ_RING_PAGES,
+            AccessStrategyKind::BulkWrite => BULK_WRITE_RING_PAGES,
+            AccessStrategyKind::Vacuum => VACUUM_RING_PAGES,
+        }
+    }
+}
+
+// 一度しか使わないページで共有のバッファプールを埋めないよう、少数のバッファを使い回すリング
+// BufferPoolManager::access_strategy で作り、fetch_page_with / create_page_with に渡す
+pub struct AccessStrategy {
+    kind: AccessStrategyKind,
+    // リングで読み込んだページとそのバッファ
+    pub(crate) ring: Vec<Option<(BufferId, PageId)>>,
+    pub(crate) current: usize,
+}
+
+impl AccessStrategy {
+    // リングの大きさはプールの 1/8 までに抑える
+    pub(crate) fn new(kind: AccessStrategyKind, pool_size: usize) -> Self {
+        let ring_size = kind.ring_pages().min(pool_size / 8).max(1);
+        Self {
+            kind,
+            ring: vec![None; ring_size],
+            current: 0,
+        }
+    }
+
+    pub fn kind(&self) -> AccessStrategyKind {
+        self.kind
+    }
+
+    pub fn ring_size(&self) -> usize {
+        self.ring.len()
+    }
+}